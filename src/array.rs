@@ -0,0 +1,340 @@
+//! Magnetometer arrays
+//!
+//! A [`SensorArray`] combines the calibrated fields of N sensors at known positions, e.g. one
+//! aligned array per trigger from [`crate::group`]. It splits them into the common-mode field,
+//! their mean, and the field gradient, which a uniform change of the Earth's field does not
+//! affect.
+//!
+//! In free space the gradient tensor is symmetric and traceless, five unknowns. A planar or
+//! three-dimensional array determines all of them, so the full tensor is fitted by least
+//! squares. Sensors on a line only give the derivative of the field along that line.
+//!
+//! Sensor axes are never perfectly parallel. [`estimate_alignments`] computes an
+//! [`Alignment`] per sensor mapping its field onto a reference sensor from samples taken while
+//! the whole array is rotated; they are applied before the array processing.
+
+use crate::calibration::alignment::{self, Alignment, FitError};
+use crate::calibration::Calibration;
+use crate::math;
+use crate::sample::Sample;
+
+/// Reason the alignments could not be estimated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlignmentError {
+    /// The fit of sensor `sensor` onto the reference failed
+    Fit { sensor: usize, error: FitError },
+    /// `reference` is not a sensor index
+    InvalidReference,
+}
+
+/// Alignment of every sensor onto sensor `reference` from simultaneous calibrated fields
+///
+/// Each entry of `samples` holds the fields of all sensors at one orientation. The alignment of
+/// the reference itself is the identity. See [`crate::calibration::alignment`] for the fit
+/// and its residuals.
+pub fn estimate_alignments<const N: usize>(
+    samples: &[[[f32; 3]; N]],
+    reference: usize,
+) -> Result<[Alignment; N], AlignmentError> {
+    if reference >= N {
+        return Err(AlignmentError::InvalidReference);
+    }
+    let mut alignments = [Alignment::IDENTITY; N];
+    for (sensor, alignment) in alignments.iter_mut().enumerate() {
+        if sensor != reference {
+            let pairs = samples
+                .iter()
+                .map(|s| (s[reference].map(|v| v as f64), s[sensor].map(|v| v as f64)));
+            *alignment = alignment::fit_vectors(pairs)
+                .map_err(|error| AlignmentError::Fit { sensor, error })?;
+        }
+    }
+    Ok(alignments)
+}
+
+/// Smallest over largest spread of the positions below which they count as a line
+const COLLINEAR_RATIO: f64 = 1e-3;
+
+/// Spatial derivative of the field in LSB/m
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gradient {
+    /// `tensor[i][j]` is ∂Bᵢ/∂xⱼ, symmetric and traceless
+    Tensor([[f32; 3]; 3]),
+    /// Sensors on a line: derivative of the field along the unit vector `direction`
+    Line {
+        direction: [f32; 3],
+        derivative: [f32; 3],
+    },
+}
+
+/// Result of [`SensorArray::process`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ArrayField<const N: usize> {
+    /// Mean of the aligned fields, the field at the array centroid
+    pub common_mode: [f32; 3],
+    /// `None` for a single sensor or sensors at the same position
+    pub gradient: Option<Gradient>,
+    /// Aligned field of each sensor minus the common mode, what the gradient does not explain
+    /// shows up here too
+    pub differential: [[f32; 3]; N],
+}
+
+/// How the gradient is solved, fixed by the geometry
+#[derive(Debug, Clone, Copy)]
+enum Solver {
+    /// Normal matrix of the 5 tensor components
+    Tensor([[f64; 5]; 5]),
+    /// Unit direction and Σ (dᵢ·u)²
+    Line([f64; 3], f64),
+    None,
+}
+
+/// Array of N sensors, see the [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct SensorArray<const N: usize> {
+    /// Positions relative to the centroid in m
+    offsets: [[f64; 3]; N],
+    alignments: [Alignment; N],
+    solver: Solver,
+}
+
+impl<const N: usize> SensorArray<N> {
+    /// Sensors at `positions` in m, in the frame of the reference sensor's axes
+    pub fn new(positions: [[f32; 3]; N]) -> Self {
+        let mut centroid = [0.0f64; 3];
+        for p in &positions {
+            for i in 0..3 {
+                centroid[i] += p[i] as f64 / N as f64;
+            }
+        }
+        let offsets = positions.map(|p| [0, 1, 2].map(|i| p[i] as f64 - centroid[i]));
+
+        let mut normal = [[0.0f64; 5]; 5];
+        let mut spread = [[0.0f64; 3]; 3];
+        for d in &offsets {
+            for row in tensor_rows(d) {
+                for i in 0..5 {
+                    for j in 0..5 {
+                        normal[i][j] += row[i] * row[j];
+                    }
+                }
+            }
+            for i in 0..3 {
+                for j in 0..3 {
+                    spread[i][j] += d[i] * d[j];
+                }
+            }
+        }
+
+        let (extent, axes) = math::sym_eigen3(spread);
+        let solver = if extent[2] <= 0.0 {
+            Solver::None
+        } else if extent[1] / extent[2] < COLLINEAR_RATIO {
+            Solver::Line([axes[0][2], axes[1][2], axes[2][2]], extent[2])
+        } else {
+            Solver::Tensor(normal)
+        };
+
+        Self {
+            offsets,
+            alignments: [Alignment::IDENTITY; N],
+            solver,
+        }
+    }
+
+    /// Apply these alignments, e.g. from [`estimate_alignments`], before processing
+    pub fn with_alignments(mut self, alignments: [Alignment; N]) -> Self {
+        self.alignments = alignments;
+        self
+    }
+
+    pub fn alignments(&self) -> &[Alignment; N] {
+        &self.alignments
+    }
+
+    /// Whether the geometry determines the full gradient tensor
+    pub fn has_tensor(&self) -> bool {
+        matches!(self.solver, Solver::Tensor(_))
+    }
+
+    /// Process simultaneous calibrated fields, in sensor order
+    pub fn process(&self, fields: &[[f32; 3]; N]) -> ArrayField<N> {
+        let mut aligned = [[0.0f64; 3]; N];
+        for ((a, alignment), field) in aligned.iter_mut().zip(&self.alignments).zip(fields) {
+            *a = alignment.apply(*field).map(|v| v as f64);
+        }
+
+        let mut mean = [0.0f64; 3];
+        for a in &aligned {
+            for i in 0..3 {
+                mean[i] += a[i] / N as f64;
+            }
+        }
+        let differential = aligned.map(|a| [0, 1, 2].map(|i| a[i] - mean[i]));
+
+        let gradient = match self.solver {
+            Solver::Tensor(normal) => {
+                let mut rhs = [0.0f64; 5];
+                for (d, b) in self.offsets.iter().zip(&differential) {
+                    for (row, b) in tensor_rows(d).iter().zip(b) {
+                        for (r, x) in rhs.iter_mut().zip(row) {
+                            *r += x * b;
+                        }
+                    }
+                }
+                math::solve(normal, rhs).map(|[xx, xy, xz, yy, yz]| {
+                    let t = [[xx, xy, xz], [xy, yy, yz], [xz, yz, -xx - yy]];
+                    Gradient::Tensor(t.map(|row| row.map(|v| v as f32)))
+                })
+            }
+            Solver::Line(u, weight) => {
+                let mut derivative = [0.0f64; 3];
+                for (d, b) in self.offsets.iter().zip(&differential) {
+                    let s = d[0] * u[0] + d[1] * u[1] + d[2] * u[2];
+                    for i in 0..3 {
+                        derivative[i] += s * b[i] / weight;
+                    }
+                }
+                Some(Gradient::Line {
+                    direction: u.map(|v| v as f32),
+                    derivative: derivative.map(|v| v as f32),
+                })
+            }
+            Solver::None => None,
+        };
+
+        ArrayField {
+            common_mode: mean.map(|v| v as f32),
+            gradient,
+            differential: differential.map(|d| d.map(|v| v as f32)),
+        }
+    }
+
+    /// Calibrate and process simultaneous samples, e.g. from a sensor group
+    pub fn process_samples(
+        &self,
+        samples: &[Sample; N],
+        calibrations: &[Calibration; N],
+    ) -> ArrayField<N> {
+        let mut fields = [[0.0; 3]; N];
+        for ((f, s), c) in fields.iter_mut().zip(samples).zip(calibrations) {
+            *f = c.apply(s);
+        }
+        self.process(&fields)
+    }
+}
+
+/// Rows of `B - B̄ = G d` in the unknowns `[Gxx, Gxy, Gxz, Gyy, Gyz]`, `Gzz = -Gxx - Gyy`
+fn tensor_rows(d: &[f64; 3]) -> [[f64; 5]; 3] {
+    let [x, y, z] = *d;
+    [
+        [x, y, z, 0.0, 0.0],
+        [0.0, x, 0.0, y, z],
+        [-z, 0.0, x, -z, y],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    const G: [[f32; 3]; 3] = [[3.0, 1.0, -2.0], [1.0, -1.0, 0.5], [-2.0, 0.5, -2.0]];
+
+    fn linear_field(b0: [f32; 3], p: [f32; 3]) -> [f32; 3] {
+        [0, 1, 2].map(|i| b0[i] + G[i][0] * p[0] + G[i][1] * p[1] + G[i][2] * p[2])
+    }
+
+    #[test]
+    fn test_gradient() {
+        // Planar square, 10 cm
+        let positions = [
+            [0.0, 0.0, 0.0],
+            [0.1, 0.0, 0.0],
+            [0.0, 0.1, 0.0],
+            [0.1, 0.1, 0.0],
+        ];
+        let square = SensorArray::new(positions);
+        assert!(square.has_tensor());
+        for b0 in [[20000.0, 0.0, 40000.0], [25000.0, -3000.0, 41000.0]] {
+            let out = square.process(&positions.map(|p| linear_field(b0, p)));
+            let Some(Gradient::Tensor(t)) = out.gradient else {
+                panic!("{:?}", out.gradient);
+            };
+            // Uniform field changes do not show up in the gradient
+            for (row, expected) in t.iter().zip(G) {
+                for (v, e) in row.iter().zip(expected) {
+                    assert!((v - e).abs() < 1e-2, "{t:?}");
+                }
+            }
+            let centroid = linear_field(b0, [0.05, 0.05, 0.0]);
+            for (c, e) in out.common_mode.iter().zip(centroid) {
+                assert!((c - e).abs() < 1e-2);
+            }
+        }
+
+        // Two sensors along y
+        let pair = SensorArray::new([[0.0, -0.2, 0.0], [0.0, 0.2, 0.0]]);
+        assert!(!pair.has_tensor());
+        let out = pair.process(&[[0.0, 0.0, 100.0], [0.0, 0.0, 140.0]]);
+        let Some(Gradient::Line {
+            direction,
+            derivative,
+        }) = out.gradient
+        else {
+            panic!("{:?}", out.gradient);
+        };
+        let sign = direction[1].signum();
+        assert!((direction[1].abs() - 1.0).abs() < 1e-6);
+        assert!((derivative[2] * sign - 100.0).abs() < 1e-3);
+        assert_eq!(out.common_mode, [0.0, 0.0, 120.0]);
+        assert_eq!(out.differential, [[0.0, 0.0, -20.0], [0.0, 0.0, 20.0]]);
+
+        assert_eq!(
+            SensorArray::new([[0.0; 3]]).process(&[[1.0; 3]]).gradient,
+            None
+        );
+    }
+
+    #[test]
+    fn test_alignment() {
+        // Sensor 1 is rotated by 0.05 rad about z and has 2 % more gain on x
+        let (s, c) = (libm::sinf(0.05), libm::cosf(0.05));
+        let target = |r: [f32; 3]| [1.02 * (c * r[0] + s * r[1]), -s * r[0] + c * r[1], r[2]];
+
+        let mut samples = Vec::new();
+        for i in 0..40 {
+            let (a, b) = (i as f32 * 0.7, i as f32 * 0.3);
+            let r = [
+                5000.0 * libm::cosf(a) * libm::cosf(b),
+                5000.0 * libm::sinf(a) * libm::cosf(b),
+                5000.0 * libm::sinf(b),
+            ];
+            samples.push([r, target(r)]);
+        }
+        let [a0, a1] = estimate_alignments(&samples, 0).unwrap();
+        assert_eq!(a0, Alignment::IDENTITY);
+        let r = [1000.0, -2000.0, 3000.0];
+        for (v, e) in a1.apply(target(r)).iter().zip(r) {
+            assert!((v - e).abs() < 0.1);
+        }
+
+        let flat: Vec<_> = samples
+            .iter()
+            .map(|s| s.map(|f| [f[0], f[1], 0.0]))
+            .collect();
+        assert_eq!(
+            estimate_alignments(&flat, 0),
+            Err(AlignmentError::Fit {
+                sensor: 1,
+                error: FitError::Coplanar
+            })
+        );
+        assert_eq!(
+            estimate_alignments(&samples, 2),
+            Err(AlignmentError::InvalidReference)
+        );
+    }
+}
@@ -0,0 +1,334 @@
+//! Hard-iron and soft-iron calibration
+//!
+//! A calibration removes the hard-iron offset `b` and applies the soft-iron matrix `M`,
+//! `h' = M (h - b)`, with the field in sensor LSB.
+//! It comes in an `f32` form ([`Calibration`]) and a fixed-point form ([`FixedCalibration`])
+//! for targets without an FPU. Both can be stored in flash with `to_bytes` / `from_bytes`.
+
+pub mod alignment;
+pub mod coverage;
+pub mod ellipsoid;
+pub mod online;
+pub mod temperature;
+
+use crate::sample::Sample;
+
+/// Length of the serialized calibration
+///
+/// Magic (2), version (1), kind (1), offset (3 x 4), matrix (9 x 4), CRC-16 (2)
+pub const ENCODED_LEN: usize = 54;
+
+/// Current version of the serialized format
+pub const FORMAT_VERSION: u8 = 1;
+
+const MAGIC: [u8; 2] = *b"AK";
+const KIND_F32: u8 = 0;
+const KIND_FIXED: u8 = 1;
+
+/// Error decoding a serialized calibration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Fewer than [`ENCODED_LEN`] bytes
+    TooShort,
+    /// Not a calibration record
+    BadMagic,
+    /// Written by an incompatible version of the format
+    UnsupportedVersion(u8),
+    /// Record holds the other (f32 / fixed-point) form
+    WrongKind(u8),
+    /// Record is corrupted
+    CrcMismatch { expected: u16, actual: u16 },
+}
+
+/// Hard-iron offset and soft-iron matrix, `f32` form
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Calibration {
+    /// Hard-iron offset X, Y, Z in LSB
+    pub offset: [f32; 3],
+    /// Soft-iron matrix, row major
+    pub soft_iron: [[f32; 3]; 3],
+}
+
+impl Default for Calibration {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl Calibration {
+    /// No correction
+    pub const IDENTITY: Self = Self {
+        offset: [0.0; 3],
+        soft_iron: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+    };
+
+    /// Calibrate a raw field vector in LSB
+    pub fn apply_raw(&self, field: [i32; 3]) -> [f32; 3] {
+        let d = [
+            field[0] as f32 - self.offset[0],
+            field[1] as f32 - self.offset[1],
+            field[2] as f32 - self.offset[2],
+        ];
+        self.soft_iron
+            .map(|row| row[0] * d[0] + row[1] * d[1] + row[2] * d[2])
+    }
+
+    /// Calibrated field of a sample, in LSB
+    pub fn apply(&self, sample: &Sample) -> [f32; 3] {
+        self.apply_raw(sample.field)
+    }
+
+    /// Convert to fixed point, saturating matrix entries outside the representable range
+    pub fn to_fixed(&self) -> FixedCalibration {
+        let scale = (1u64 << FixedCalibration::FRAC_BITS) as f32;
+        FixedCalibration {
+            offset: self.offset.map(round_to_i32),
+            soft_iron: self
+                .soft_iron
+                .map(|row| row.map(|m| round_to_i32(m * scale))),
+        }
+    }
+
+    /// Serialize to the versioned flash format
+    pub fn to_bytes(&self) -> [u8; ENCODED_LEN] {
+        let mut words = [0u32; 12];
+        for (w, v) in words
+            .iter_mut()
+            .zip(self.offset.iter().chain(self.soft_iron.iter().flatten()))
+        {
+            *w = v.to_bits();
+        }
+        encode(KIND_F32, &words)
+    }
+
+    /// Deserialize from the versioned flash format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let words = decode(KIND_F32, bytes)?;
+        let mut cal = Self::IDENTITY;
+        for (v, w) in cal
+            .offset
+            .iter_mut()
+            .chain(cal.soft_iron.iter_mut().flatten())
+            .zip(words)
+        {
+            *v = f32::from_bits(w);
+        }
+        Ok(cal)
+    }
+}
+
+/// Hard-iron offset and soft-iron matrix, fixed-point form
+///
+/// The matrix is in Q2.29, which covers entries in (-4, 4) and keeps the rounding error
+/// well below one LSB over the full 24-bit range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedCalibration {
+    /// Hard-iron offset X, Y, Z in LSB
+    pub offset: [i32; 3],
+    /// Soft-iron matrix, row major, Q2.29
+    pub soft_iron: [[i32; 3]; 3],
+}
+
+impl Default for FixedCalibration {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl FixedCalibration {
+    /// Number of fractional bits of the matrix entries
+    pub const FRAC_BITS: u32 = 29;
+
+    const ONE: i32 = 1 << Self::FRAC_BITS;
+
+    /// No correction
+    pub const IDENTITY: Self = Self {
+        offset: [0; 3],
+        soft_iron: [[Self::ONE, 0, 0], [0, Self::ONE, 0], [0, 0, Self::ONE]],
+    };
+
+    /// Calibrate a raw field vector in LSB
+    pub fn apply_raw(&self, field: [i32; 3]) -> [i32; 3] {
+        let d = [
+            field[0] as i64 - self.offset[0] as i64,
+            field[1] as i64 - self.offset[1] as i64,
+            field[2] as i64 - self.offset[2] as i64,
+        ];
+        self.soft_iron.map(|row| {
+            let acc = row[0] as i64 * d[0] + row[1] as i64 * d[1] + row[2] as i64 * d[2];
+            // Round to nearest
+            ((acc + (1 << (Self::FRAC_BITS - 1))) >> Self::FRAC_BITS) as i32
+        })
+    }
+
+    /// Calibrated field of a sample, in LSB
+    pub fn apply(&self, sample: &Sample) -> [i32; 3] {
+        self.apply_raw(sample.field)
+    }
+
+    /// Convert to `f32`
+    pub fn to_f32(&self) -> Calibration {
+        let scale = 1.0 / (1u64 << Self::FRAC_BITS) as f32;
+        Calibration {
+            offset: self.offset.map(|b| b as f32),
+            soft_iron: self.soft_iron.map(|row| row.map(|m| m as f32 * scale)),
+        }
+    }
+
+    /// Serialize to the versioned flash format
+    pub fn to_bytes(&self) -> [u8; ENCODED_LEN] {
+        let mut words = [0u32; 12];
+        for (w, v) in words
+            .iter_mut()
+            .zip(self.offset.iter().chain(self.soft_iron.iter().flatten()))
+        {
+            *w = *v as u32;
+        }
+        encode(KIND_FIXED, &words)
+    }
+
+    /// Deserialize from the versioned flash format
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let words = decode(KIND_FIXED, bytes)?;
+        let mut cal = Self::IDENTITY;
+        for (v, w) in cal
+            .offset
+            .iter_mut()
+            .chain(cal.soft_iron.iter_mut().flatten())
+            .zip(words)
+        {
+            *v = w as i32;
+        }
+        Ok(cal)
+    }
+}
+
+fn round_to_i32(v: f32) -> i32 {
+    // `as` saturates, NaN becomes 0
+    if v >= 0.0 {
+        (v + 0.5) as i32
+    } else {
+        (v - 0.5) as i32
+    }
+}
+
+fn encode(kind: u8, words: &[u32; 12]) -> [u8; ENCODED_LEN] {
+    let mut buf = [0u8; ENCODED_LEN];
+    buf[0..2].copy_from_slice(&MAGIC);
+    buf[2] = FORMAT_VERSION;
+    buf[3] = kind;
+    for (chunk, w) in buf[4..52].chunks_exact_mut(4).zip(words) {
+        chunk.copy_from_slice(&w.to_le_bytes());
+    }
+    let crc = crc16(&buf[..52]);
+    buf[52..].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+fn decode(kind: u8, bytes: &[u8]) -> Result<[u32; 12], DecodeError> {
+    if bytes.len() < ENCODED_LEN {
+        return Err(DecodeError::TooShort);
+    }
+    let bytes = &bytes[..ENCODED_LEN];
+    if bytes[0..2] != MAGIC {
+        return Err(DecodeError::BadMagic);
+    }
+    if bytes[2] != FORMAT_VERSION {
+        return Err(DecodeError::UnsupportedVersion(bytes[2]));
+    }
+    let expected = crc16(&bytes[..52]);
+    let actual = u16::from_le_bytes([bytes[52], bytes[53]]);
+    if expected != actual {
+        return Err(DecodeError::CrcMismatch { expected, actual });
+    }
+    if bytes[3] != kind {
+        return Err(DecodeError::WrongKind(bytes[3]));
+    }
+
+    let mut words = [0u32; 12];
+    for (w, chunk) in words.iter_mut().zip(bytes[4..52].chunks_exact(4)) {
+        *w = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+    }
+    Ok(words)
+}
+
+/// CRC-16/CCITT-FALSE (poly 0x1021, init 0xFFFF)
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn example() -> Calibration {
+        Calibration {
+            offset: [1200.5, -340.0, 25.25],
+            soft_iron: [
+                [1.02, 0.01, -0.003],
+                [0.01, 0.97, 0.002],
+                [-0.003, 0.002, 1.01],
+            ],
+        }
+    }
+
+    #[test]
+    fn test_crc16() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let cal = example();
+        let bytes = cal.to_bytes();
+        assert_eq!(Calibration::from_bytes(&bytes), Ok(cal));
+        assert_eq!(
+            FixedCalibration::from_bytes(&bytes),
+            Err(DecodeError::WrongKind(KIND_F32))
+        );
+
+        let fixed = cal.to_fixed();
+        assert_eq!(FixedCalibration::from_bytes(&fixed.to_bytes()), Ok(fixed));
+
+        let mut corrupted = bytes;
+        corrupted[10] ^= 0x01;
+        assert!(matches!(
+            Calibration::from_bytes(&corrupted),
+            Err(DecodeError::CrcMismatch { .. })
+        ));
+        assert_eq!(
+            Calibration::from_bytes(&bytes[..20]),
+            Err(DecodeError::TooShort)
+        );
+    }
+
+    #[test]
+    fn test_fixed_matches_f32() {
+        let cal = example();
+        let fixed = cal.to_fixed();
+        for field in [
+            [0, 0, 0],
+            [8_000_000, -8_000_000, 12345],
+            [-4567, 100, 5_000_000],
+        ] {
+            let f = cal.apply_raw(field);
+            let q = fixed.apply_raw(field);
+            for i in 0..3 {
+                // Offsets are rounded to whole LSB in fixed point
+                assert!((f[i] - q[i] as f32).abs() < 2.0 + f[i].abs() * 1e-6);
+            }
+        }
+        assert_eq!(FixedCalibration::IDENTITY.apply_raw([1, -2, 3]), [1, -2, 3]);
+    }
+}
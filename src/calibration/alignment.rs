@@ -0,0 +1,256 @@
+//! Cross-sensor alignment
+//!
+//! Two sensors on one board see the same field, but through axes that are slightly rotated
+//! against each other and with different scale factors. From simultaneous raw readings of a
+//! reference and a target sensor, taken while the board is rotated, this solves for
+//!
+//! `r = M t + c`
+//!
+//! by least squares, with `M` the matrix mapping the target's field onto the reference's axes
+//! and `c` absorbing the hard-iron offsets of both. `M` is split into a rotation and a
+//! symmetric scale matrix (polar decomposition), and the residuals show how well the model
+//! fits.
+
+use crate::math::{self, Mat3};
+use crate::sample::Sample;
+
+/// Reason an alignment was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitError {
+    /// Not enough sample pairs, 4 are needed
+    TooFewSamples { required: usize, provided: usize },
+    /// The target fields (nearly) lie in a plane, rotate the board about more axes
+    Coplanar,
+}
+
+/// Result of a fit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alignment {
+    /// `M`, maps the target's field onto the reference's axes
+    pub matrix: [[f32; 3]; 3],
+    /// `c` in LSB
+    pub offset: [f32; 3],
+    /// Rotation part of `M`, its determinant is -1 if the target's axes are mirrored, e.g. by
+    /// a mounting that was not accounted for
+    pub rotation: [[f32; 3]; 3],
+    /// Symmetric scale part of `M`, `M = rotation * scale`
+    pub scale: [[f32; 3]; 3],
+    /// Residuals of the fit
+    pub residuals: Residuals,
+}
+
+/// Distance between the reference and the mapped target, in LSB
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Residuals {
+    pub rms: f32,
+    pub max: f32,
+    /// RMS per reference axis
+    pub axis_rms: [f32; 3],
+}
+
+impl Alignment {
+    /// No rotation, scaling or offset
+    pub const IDENTITY: Self = Self {
+        matrix: IDENTITY,
+        offset: [0.0; 3],
+        rotation: IDENTITY,
+        scale: IDENTITY,
+        residuals: Residuals {
+            rms: 0.0,
+            max: 0.0,
+            axis_rms: [0.0; 3],
+        },
+    };
+
+    /// Map a target field onto the reference's axes
+    pub fn apply(&self, target: [f32; 3]) -> [f32; 3] {
+        [0, 1, 2].map(|i| {
+            let row = self.matrix[i];
+            row[0] * target[0] + row[1] * target[1] + row[2] * target[2] + self.offset[i]
+        })
+    }
+
+    /// Angle of the rotation part in radians
+    pub fn angle(&self) -> f32 {
+        let r = &self.rotation;
+        let trace = r[0][0] + r[1][1] + r[2][2];
+        libm::acosf(((trace - 1.0) / 2.0).clamp(-1.0, 1.0))
+    }
+}
+
+const IDENTITY: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+/// Minimum number of sample pairs for the fit to be determined
+pub const MIN_SAMPLES: usize = 4;
+
+/// Smallest over largest spread of the target fields below which they count as coplanar
+const COPLANAR_RATIO: f64 = 1e-3;
+
+/// Fit the alignment of `target` onto `reference` from samples read at the same instants
+///
+/// The samples are paired up in order, extra samples on either side are ignored.
+pub fn fit(reference: &[Sample], target: &[Sample]) -> Result<Alignment, FitError> {
+    fit_fields(
+        reference
+            .iter()
+            .zip(target)
+            .map(|(r, t)| (r.field, t.field)),
+    )
+}
+
+/// Fit the alignment from pairs of raw `(reference, target)` fields in LSB
+pub fn fit_fields<I>(pairs: I) -> Result<Alignment, FitError>
+where
+    I: Iterator<Item = ([i32; 3], [i32; 3])> + Clone,
+{
+    fit_vectors(pairs.map(|(r, t)| (r.map(|v| v as f64), t.map(|v| v as f64))))
+}
+
+pub(crate) fn fit_vectors<I>(pairs: I) -> Result<Alignment, FitError>
+where
+    I: Iterator<Item = ([f64; 3], [f64; 3])> + Clone,
+{
+    let provided = pairs.clone().count();
+    if provided < MIN_SAMPLES {
+        return Err(FitError::TooFewSamples {
+            required: MIN_SAMPLES,
+            provided,
+        });
+    }
+    let n = provided as f64;
+
+    // Centering removes c, then (Σ t tᵀ) mᵢ = Σ rᵢ t for every row mᵢ of M
+    let mut mean_r = [0.0f64; 3];
+    let mut mean_t = [0.0f64; 3];
+    for (r, t) in pairs.clone() {
+        for i in 0..3 {
+            mean_r[i] += r[i] / n;
+            mean_t[i] += t[i] / n;
+        }
+    }
+    let mut tt = [[0.0f64; 3]; 3];
+    let mut rt = [[0.0f64; 3]; 3];
+    for (r, t) in pairs.clone() {
+        let dr = [0, 1, 2].map(|i| r[i] - mean_r[i]);
+        let dt = [0, 1, 2].map(|i| t[i] - mean_t[i]);
+        for i in 0..3 {
+            for j in 0..3 {
+                tt[i][j] += dt[i] * dt[j];
+                rt[i][j] += dr[i] * dt[j];
+            }
+        }
+    }
+    let (spread, _) = math::sym_eigen3(tt);
+    if spread[2] <= 0.0 || spread[0] / spread[2] < COPLANAR_RATIO {
+        return Err(FitError::Coplanar);
+    }
+
+    let mut m = [[0.0f64; 3]; 3];
+    for (row, rhs) in m.iter_mut().zip(rt) {
+        *row = math::solve(tt, rhs).ok_or(FitError::Coplanar)?;
+    }
+    let mt = math::mat_vec(&m, &mean_t);
+    let c = [0, 1, 2].map(|i| mean_r[i] - mt[i]);
+
+    // M = R S with S = sqrt(MᵀM)
+    let (eig, v) = math::sym_eigen3(math::mat_mul(&math::transpose(&m), &m));
+    if eig[0] <= 0.0 {
+        return Err(FitError::Coplanar);
+    }
+    let scale = math::from_eigen(eig.map(libm::sqrt), &v);
+    let rotation = math::mat_mul(&m, &math::from_eigen(eig.map(|e| 1.0 / libm::sqrt(e)), &v));
+
+    let mut axis_sq = [0.0f64; 3];
+    let mut max = 0.0f64;
+    for (r, t) in pairs {
+        let mapped = math::mat_vec(&m, &t);
+        let e = [0, 1, 2].map(|i| r[i] - mapped[i] - c[i]);
+        for (sq, e) in axis_sq.iter_mut().zip(e) {
+            *sq += e * e / n;
+        }
+        max = max.max(math::norm(&e));
+    }
+
+    Ok(Alignment {
+        matrix: to_f32(&m),
+        offset: c.map(|v| v as f32),
+        rotation: to_f32(&rotation),
+        scale: to_f32(&scale),
+        residuals: Residuals {
+            rms: libm::sqrt(axis_sq.iter().sum()) as f32,
+            max: max as f32,
+            axis_rms: axis_sq.map(|sq| libm::sqrt(sq) as f32),
+        },
+    })
+}
+
+fn to_f32(m: &Mat3) -> [[f32; 3]; 3] {
+    m.map(|row| row.map(|v| v as f32))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn test_fit() {
+        // Target rotated by 2° about z and 1° about x, 3 % more gain on y, own offset
+        let (sz, cz) = (libm::sin(2f64.to_radians()), libm::cos(2f64.to_radians()));
+        let (sx, cx) = (libm::sin(1f64.to_radians()), libm::cos(1f64.to_radians()));
+        let rz = [[cz, -sz, 0.0], [sz, cz, 0.0], [0.0, 0.0, 1.0]];
+        let rx = [[1.0, 0.0, 0.0], [0.0, cx, -sx], [0.0, sx, cx]];
+        let r = math::mat_mul(&rz, &rx);
+        let gain = [[1.0, 0.0, 0.0], [0.0, 1.03, 0.0], [0.0, 0.0, 1.0]];
+
+        let mut pairs = Vec::new();
+        for i in 0..60 {
+            let (a, b) = (i as f64 * 0.7, i as f64 * 0.31);
+            let field = [
+                5000.0 * libm::cos(a) * libm::cos(b),
+                5000.0 * libm::sin(a) * libm::cos(b),
+                5000.0 * libm::sin(b),
+            ];
+            // Reference sees the field with its own offset
+            let reference = [0, 1, 2].map(|k| (field[k] + [100.0, -50.0, 20.0][k]) as i32);
+            // target = gain Rᵀ field + offset, so field = R gain⁻¹ (target - offset)
+            let seen = math::mat_vec(&gain, &math::mat_vec(&math::transpose(&r), &field));
+            let target = [0, 1, 2].map(|k| (seen[k] + [-300.0, 700.0, 0.0][k]) as i32);
+            pairs.push((reference, target));
+        }
+
+        let fit = fit_fields(pairs.iter().copied()).unwrap();
+        let expected_angle = libm::acos(((cz + cz * cx + cx) - 1.0) / 2.0) as f32;
+        assert!(
+            (fit.angle() - expected_angle).abs() < 1e-3,
+            "{}",
+            fit.angle()
+        );
+        assert!((fit.scale[1][1] - 1.0 / 1.03).abs() < 1e-3);
+        assert!((fit.scale[0][0] - 1.0).abs() < 1e-3);
+        for (row, expected) in fit.rotation.iter().zip(r) {
+            for (v, e) in row.iter().zip(expected) {
+                assert!((v - e as f32).abs() < 1e-3);
+            }
+        }
+        // Only rounding to whole LSB is left
+        assert!(fit.residuals.rms < 1.5, "{:?}", fit.residuals);
+        assert!(fit.residuals.max < 3.0);
+        let mapped = fit.apply(pairs[5].1.map(|v| v as f32));
+        for (m, r) in mapped.iter().zip(pairs[5].0) {
+            assert!((m - r as f32).abs() < 3.0);
+        }
+
+        assert_eq!(
+            fit_fields(pairs[..3].iter().copied()),
+            Err(FitError::TooFewSamples {
+                required: 4,
+                provided: 3
+            })
+        );
+        let flat = pairs.iter().map(|&(r, t)| (r, [t[0], t[1], 0]));
+        assert_eq!(fit_fields(flat), Err(FitError::Coplanar));
+    }
+}
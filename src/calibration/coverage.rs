@@ -0,0 +1,186 @@
+//! Sphere coverage tracking for guided calibration
+//!
+//! Bins the direction of each sample into a fixed grid on the unit sphere and reports how much
+//! of it has been visited. The grid uses bands of equal height in Z and sectors of equal azimuth,
+//! which makes every bin the same area (Archimedes' hat-box theorem), so the coverage percentage
+//! is an honest fraction of the sphere.
+
+use crate::sample::Sample;
+
+/// One bin of the coverage grid
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    /// Band index, 0 is the bottom (-Z) band
+    pub band: usize,
+    /// Sector index, counted from +X towards +Y
+    pub sector: usize,
+    /// Unit vector pointing at the center of the bin
+    pub direction: [f32; 3],
+}
+
+/// Tracks which directions on the sphere have been seen
+///
+/// `BANDS` x `SECTORS` bins, a bin counts as covered after `min_hits` samples.
+#[derive(Debug, Clone)]
+pub struct CoverageTracker<const BANDS: usize, const SECTORS: usize> {
+    center: [f32; 3],
+    hits: [[u8; SECTORS]; BANDS],
+    min_hits: u8,
+}
+
+/// 8 bands x 16 sectors, each bin about 1/128 of the sphere
+pub type DefaultCoverageTracker = CoverageTracker<8, 16>;
+
+impl<const BANDS: usize, const SECTORS: usize> Default for CoverageTracker<BANDS, SECTORS> {
+    fn default() -> Self {
+        Self::new([0.0; 3], 1)
+    }
+}
+
+impl<const BANDS: usize, const SECTORS: usize> CoverageTracker<BANDS, SECTORS> {
+    /// Create a tracker
+    ///
+    /// `center` is the (approximate) hard-iron offset in LSB. Directions are taken relative
+    /// to it, so a large uncorrected offset does not squeeze all samples into a few bins.
+    pub fn new(center: [f32; 3], min_hits: u8) -> Self {
+        Self {
+            center,
+            hits: [[0; SECTORS]; BANDS],
+            min_hits: min_hits.max(1),
+        }
+    }
+
+    /// Update the center, e.g. from an online hard-iron estimate
+    ///
+    /// Bins already visited stay visited.
+    pub fn set_center(&mut self, center: [f32; 3]) {
+        self.center = center;
+    }
+
+    /// Start over
+    pub fn clear(&mut self) {
+        self.hits = [[0; SECTORS]; BANDS];
+    }
+
+    /// Feed a sample, samples flagged invalid by the sensor are skipped
+    pub fn update(&mut self, sample: &Sample) {
+        if !sample.is_invalid() {
+            self.update_field(sample.field);
+        }
+    }
+
+    /// Feed a raw field vector in LSB
+    pub fn update_field(&mut self, field: [i32; 3]) {
+        let d = [0, 1, 2].map(|i| field[i] as f32 - self.center[i]);
+        self.update_direction(d);
+    }
+
+    /// Feed a direction, it does not need to be normalized
+    pub fn update_direction(&mut self, d: [f32; 3]) {
+        let norm = libm::sqrtf(d[0] * d[0] + d[1] * d[1] + d[2] * d[2]);
+        if norm == 0.0 || !norm.is_finite() {
+            return;
+        }
+        let z = d[2] / norm;
+        let band = (((z + 1.0) * 0.5 * BANDS as f32) as usize).min(BANDS - 1);
+        let mut azimuth = libm::atan2f(d[1], d[0]);
+        if azimuth < 0.0 {
+            azimuth += core::f32::consts::TAU;
+        }
+        let sector =
+            ((azimuth / core::f32::consts::TAU * SECTORS as f32) as usize).min(SECTORS - 1);
+
+        let hits = &mut self.hits[band][sector];
+        *hits = hits.saturating_add(1);
+    }
+
+    /// Number of bins in the grid
+    pub const fn bins(&self) -> usize {
+        BANDS * SECTORS
+    }
+
+    /// Number of covered bins
+    pub fn covered(&self) -> usize {
+        self.hits
+            .iter()
+            .flatten()
+            .filter(|&&h| h >= self.min_hits)
+            .count()
+    }
+
+    /// Covered fraction of the sphere in percent
+    pub fn percent(&self) -> f32 {
+        self.covered() as f32 * 100.0 / self.bins() as f32
+    }
+
+    /// Whether a bin is covered
+    pub fn is_covered(&self, band: usize, sector: usize) -> bool {
+        self.hits[band][sector] >= self.min_hits
+    }
+
+    /// Bins that still need samples
+    pub fn missing(&self) -> impl Iterator<Item = Region> + '_ {
+        (0..BANDS)
+            .flat_map(|band| (0..SECTORS).map(move |sector| (band, sector)))
+            .filter(|&(band, sector)| !self.is_covered(band, sector))
+            .map(|(band, sector)| Region {
+                band,
+                sector,
+                direction: Self::bin_center(band, sector),
+            })
+    }
+
+    /// Unit vector at the center of a bin
+    pub fn bin_center(band: usize, sector: usize) -> [f32; 3] {
+        let z = (band as f32 + 0.5) / BANDS as f32 * 2.0 - 1.0;
+        let azimuth = (sector as f32 + 0.5) / SECTORS as f32 * core::f32::consts::TAU;
+        let r = libm::sqrtf(1.0 - z * z);
+        [r * libm::cosf(azimuth), r * libm::sinf(azimuth), z]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coverage() {
+        let offset = [5000.0, -2000.0, 300.0];
+        let mut tracker = CoverageTracker::<4, 8>::new(offset, 2);
+        assert_eq!(tracker.covered(), 0);
+        assert_eq!(tracker.missing().count(), 32);
+
+        // Every bin center, around the hard-iron offset
+        let visit = |tracker: &mut CoverageTracker<4, 8>| {
+            for band in 0..4 {
+                for sector in 0..8 {
+                    let c = CoverageTracker::<4, 8>::bin_center(band, sector);
+                    tracker.update_field([0, 1, 2].map(|i| (offset[i] + c[i] * 1000.0) as i32));
+                }
+            }
+        };
+        visit(&mut tracker);
+        // Two hits needed per bin
+        assert_eq!(tracker.covered(), 0);
+        visit(&mut tracker);
+        assert_eq!(tracker.percent(), 100.0);
+        assert_eq!(tracker.missing().count(), 0);
+
+        tracker.clear();
+        assert_eq!(tracker.percent(), 0.0);
+    }
+
+    #[test]
+    fn test_missing_regions() {
+        let mut tracker = DefaultCoverageTracker::default();
+        tracker.update_direction([0.0, 0.0, 1.0]);
+        tracker.update_direction([0.0, 0.0, -1.0]);
+        assert_eq!(tracker.covered(), 2);
+        assert!(tracker.is_covered(7, 0));
+        assert!(tracker.is_covered(0, 0));
+        assert_eq!(tracker.missing().count(), 126);
+        assert!(tracker
+            .missing()
+            .all(|r| r.sector != 0 || (1..7).contains(&r.band)));
+    }
+}
@@ -0,0 +1,292 @@
+//! Least-squares ellipsoid fit
+//!
+//! Computes a [`Calibration`] from samples collected while the device is rotated through
+//! as many orientations as possible. The general fit solves for the nine parameters of
+//!
+//! `a x² + b y² + c z² + 2d xy + 2e xz + 2f yz + 2g x + 2h y + 2i z = 1`
+//!
+//! and derives the soft-iron matrix from the symmetric square root of the quadric.
+//! The sphere fit only estimates the hard-iron offset and the field radius.
+//!
+//! The samples are centered and scaled before fitting, so the normal equations stay well
+//! conditioned over the full 24-bit range.
+
+use super::Calibration;
+use crate::math::{self, Mat3};
+use crate::sample::Sample;
+
+/// What to solve for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitMode {
+    /// Hard-iron offset only, soft-iron matrix is the identity
+    Sphere,
+    /// Hard-iron offset and a full symmetric soft-iron matrix
+    Ellipsoid,
+}
+
+impl FitMode {
+    /// Minimum number of samples for the fit to be determined
+    pub const fn min_samples(self) -> usize {
+        match self {
+            FitMode::Sphere => 4,
+            FitMode::Ellipsoid => 9,
+        }
+    }
+}
+
+/// Reason a fit was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FitError {
+    /// Not enough samples for the fit mode
+    TooFewSamples { required: usize, provided: usize },
+    /// The samples (nearly) lie in a plane or on a line, rotate the device about more axes
+    Coplanar,
+    /// The normal equations are singular
+    Singular,
+    /// The best fitting quadric is not an ellipsoid
+    NotAnEllipsoid,
+}
+
+/// Result of a fit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fit {
+    pub calibration: Calibration,
+    /// Radius of the calibrated field sphere in LSB, i.e. the local field strength
+    pub radius: f32,
+    /// RMS deviation of the calibrated samples from the sphere, in LSB
+    pub residual: f32,
+    /// Quality score from 0 (useless) to 1
+    ///
+    /// Combines the relative residual (0 at 5 % of the radius) with how evenly the
+    /// samples span the three axes.
+    pub quality: f32,
+}
+
+/// Smallest over largest spread of the samples below which they count as coplanar
+const COPLANAR_RATIO: f64 = 1e-3;
+
+/// Relative residual at which the quality score drops to zero
+const RESIDUAL_LIMIT: f64 = 0.05;
+
+/// Fit a calibration to samples from `read_sample`
+pub fn fit(samples: &[Sample], mode: FitMode) -> Result<Fit, FitError> {
+    fit_fields(samples.iter().map(|s| s.field), mode)
+}
+
+/// Fit a calibration to raw field vectors in LSB
+pub fn fit_fields<I>(fields: I, mode: FitMode) -> Result<Fit, FitError>
+where
+    I: Iterator<Item = [i32; 3]> + Clone,
+{
+    let provided = fields.clone().count();
+    let required = mode.min_samples();
+    if provided < required {
+        return Err(FitError::TooFewSamples { required, provided });
+    }
+    let n = provided as f64;
+
+    // Normalize: u = (h - mean) / scale
+    let mut mean = [0.0f64; 3];
+    for f in fields.clone() {
+        for i in 0..3 {
+            mean[i] += f[i] as f64 / n;
+        }
+    }
+    let mut cov = [[0.0f64; 3]; 3];
+    for f in fields.clone() {
+        let d = [0, 1, 2].map(|i| f[i] as f64 - mean[i]);
+        for i in 0..3 {
+            for j in 0..3 {
+                cov[i][j] += d[i] * d[j] / n;
+            }
+        }
+    }
+    let (spread, _) = math::sym_eigen3(cov);
+    if spread[2] <= 0.0 || spread[0] / spread[2] < COPLANAR_RATIO {
+        return Err(FitError::Coplanar);
+    }
+    let scale = libm::sqrt(cov[0][0] + cov[1][1] + cov[2][2]);
+    let normalized = fields.map(move |f| [0, 1, 2].map(|i| (f[i] as f64 - mean[i]) / scale));
+
+    // Center (normalized) and the matrix mapping u - center onto the unit sphere
+    let (center, shape) = match mode {
+        FitMode::Sphere => fit_sphere(normalized.clone())?,
+        FitMode::Ellipsoid => fit_ellipsoid(normalized.clone())?,
+    };
+
+    // Semi-axes in LSB are scale / sqrt(eigenvalue), the radius is their geometric mean
+    let (eig, vectors) = math::sym_eigen3(shape);
+    if eig[0] <= 0.0 {
+        return Err(FitError::NotAnEllipsoid);
+    }
+    let radius = scale / libm::cbrt(libm::sqrt(eig[0] * eig[1] * eig[2]));
+    let soft_iron = math::from_eigen(eig.map(|e| libm::sqrt(e) * radius / scale), &vectors);
+
+    let mut sum_sq = 0.0;
+    for u in normalized {
+        let d = [0, 1, 2].map(|i| (u[i] - center[i]) * scale);
+        let r = math::norm(&math::mat_vec(&soft_iron, &d));
+        sum_sq += (r - radius) * (r - radius);
+    }
+    let residual = libm::sqrt(sum_sq / n);
+
+    let fit_quality = (1.0 - residual / radius / RESIDUAL_LIMIT).clamp(0.0, 1.0);
+    let coverage = libm::sqrt(spread[0] / spread[2]);
+
+    Ok(Fit {
+        calibration: Calibration {
+            offset: [0, 1, 2].map(|i| (mean[i] + center[i] * scale) as f32),
+            soft_iron: soft_iron.map(|row| row.map(|m| m as f32)),
+        },
+        radius: radius as f32,
+        residual: residual as f32,
+        quality: (fit_quality * coverage) as f32,
+    })
+}
+
+/// `|u|² = 2 c·u + k`, returns the center and `I / r²`
+fn fit_sphere<I>(points: I) -> Result<([f64; 3], Mat3), FitError>
+where
+    I: Iterator<Item = [f64; 3]>,
+{
+    let mut ata = [[0.0; 4]; 4];
+    let mut atb = [0.0; 4];
+    for u in points {
+        let row = [2.0 * u[0], 2.0 * u[1], 2.0 * u[2], 1.0];
+        let rhs = u[0] * u[0] + u[1] * u[1] + u[2] * u[2];
+        accumulate(&mut ata, &mut atb, &row, rhs);
+    }
+    let p = math::solve(ata, atb).ok_or(FitError::Singular)?;
+
+    let center = [p[0], p[1], p[2]];
+    let r2 = p[3] + center[0] * center[0] + center[1] * center[1] + center[2] * center[2];
+    if r2 <= 0.0 {
+        return Err(FitError::NotAnEllipsoid);
+    }
+    let shape = math::IDENTITY3.map(|row| row.map(|v| v / r2));
+    Ok((center, shape))
+}
+
+/// General quadric through the points, returns the center and `A / k`
+/// such that `(u - c)ᵀ (A / k) (u - c) = 1`
+fn fit_ellipsoid<I>(points: I) -> Result<([f64; 3], Mat3), FitError>
+where
+    I: Iterator<Item = [f64; 3]>,
+{
+    let mut ata = [[0.0; 9]; 9];
+    let mut atb = [0.0; 9];
+    for [x, y, z] in points {
+        let row = [
+            x * x,
+            y * y,
+            z * z,
+            2.0 * x * y,
+            2.0 * x * z,
+            2.0 * y * z,
+            2.0 * x,
+            2.0 * y,
+            2.0 * z,
+        ];
+        accumulate(&mut ata, &mut atb, &row, 1.0);
+    }
+    let p = math::solve(ata, atb).ok_or(FitError::Singular)?;
+
+    let a = [[p[0], p[3], p[4]], [p[3], p[1], p[5]], [p[4], p[5], p[2]]];
+    let v = [p[6], p[7], p[8]];
+    let center = math::solve(a, v.map(|x| -x)).ok_or(FitError::NotAnEllipsoid)?;
+    let ac = math::mat_vec(&a, &center);
+    let k = 1.0 + center[0] * ac[0] + center[1] * ac[1] + center[2] * ac[2];
+    if k <= 0.0 {
+        return Err(FitError::NotAnEllipsoid);
+    }
+    Ok((center, a.map(|row| row.map(|x| x / k))))
+}
+
+fn accumulate<const N: usize>(
+    ata: &mut [[f64; N]; N],
+    atb: &mut [f64; N],
+    row: &[f64; N],
+    rhs: f64,
+) {
+    for i in 0..N {
+        for j in 0..N {
+            ata[i][j] += row[i] * row[j];
+        }
+        atb[i] += row[i] * rhs;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    /// Points of a distorted sphere, roughly uniform over orientations
+    fn distorted(offset: [f64; 3], m: Mat3, radius: f64) -> Vec<[i32; 3]> {
+        let mut out = Vec::new();
+        for i in 0..12 {
+            // Uniform in z, hence in area
+            let theta = libm::acos(1.0 - 2.0 * (i as f64 + 0.5) / 12.0);
+            for j in 0..24 {
+                let phi = j as f64 / 24.0 * 2.0 * core::f64::consts::PI;
+                let s = [
+                    radius * libm::sin(theta) * libm::cos(phi),
+                    radius * libm::sin(theta) * libm::sin(phi),
+                    radius * libm::cos(theta),
+                ];
+                let d = math::mat_vec(&m, &s);
+                out.push([0, 1, 2].map(|k| (d[k] + offset[k]) as i32));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_sphere() {
+        let points = distorted([12000.0, -3000.0, 450.0], math::IDENTITY3, 5000.0);
+        let fit = fit_fields(points.iter().copied(), FitMode::Sphere).unwrap();
+        let b = fit.calibration.offset;
+        assert!((b[0] - 12000.0).abs() < 1.0);
+        assert!((b[1] + 3000.0).abs() < 1.0);
+        assert!((b[2] - 450.0).abs() < 1.0);
+        assert!((fit.radius - 5000.0).abs() < 2.0);
+        assert!(fit.residual < 1.0);
+        assert!(fit.quality > 0.9);
+    }
+
+    #[test]
+    fn test_ellipsoid() {
+        let m = [[1.2, 0.05, 0.0], [0.05, 0.9, 0.02], [0.0, 0.02, 1.05]];
+        let points = distorted([-8000.0, 2500.0, 7000.0], m, 4000.0);
+        let fit = fit_fields(points.iter().copied(), FitMode::Ellipsoid).unwrap();
+        assert!(fit.residual < 2.0);
+
+        // Every calibrated sample ends up on the sphere
+        for p in &points {
+            let c = fit.calibration.apply_raw(*p);
+            let r = libm::sqrtf(c[0] * c[0] + c[1] * c[1] + c[2] * c[2]);
+            assert!((r - fit.radius).abs() < 5.0);
+        }
+        let b = fit.calibration.offset;
+        assert!((b[0] + 8000.0).abs() < 2.0);
+        assert!((b[1] - 2500.0).abs() < 2.0);
+        assert!((b[2] - 7000.0).abs() < 2.0);
+    }
+
+    #[test]
+    fn test_degenerate() {
+        let points = distorted([0.0; 3], math::IDENTITY3, 1000.0);
+        assert_eq!(
+            fit_fields(points.iter().copied().take(5), FitMode::Ellipsoid),
+            Err(FitError::TooFewSamples {
+                required: 9,
+                provided: 5
+            })
+        );
+
+        let flat = points.iter().map(|p| [p[0], p[1], 100]);
+        assert_eq!(fit_fields(flat, FitMode::Sphere), Err(FitError::Coplanar));
+    }
+}
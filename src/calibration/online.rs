@@ -0,0 +1,294 @@
+//! Online hard-iron estimation
+//!
+//! Recursive least squares on the sphere model `|h|² = 2 b·h + k`, updated with every sample
+//! of the continuous stream. A forgetting factor lets the estimate follow slow changes of the
+//! hard-iron offset, and the state is a fixed 4x4 covariance, so memory use is bounded.
+//!
+//! The offset is only observable along directions the device has actually been rotated through,
+//! [`HardIronEstimator::uncertainty`] reflects that.
+
+use super::Calibration;
+use crate::math;
+use crate::sample::Sample;
+
+/// Tuning of the [`HardIronEstimator`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OnlineConfig {
+    /// Forgetting factor in (0, 1], 1 never forgets
+    ///
+    /// The effective memory is about `1 / (1 - forgetting)` samples.
+    pub forgetting: f32,
+    /// Offset uncertainty in LSB below which the estimate counts as converged
+    pub converged_uncertainty: f32,
+    /// Minimum number of samples before the estimate can count as converged
+    pub min_samples: u32,
+}
+
+impl Default for OnlineConfig {
+    fn default() -> Self {
+        Self {
+            forgetting: 0.999,
+            converged_uncertainty: 50.0,
+            min_samples: 100,
+        }
+    }
+}
+
+/// Initial covariance of the (normalized) parameters
+const INITIAL_COVARIANCE: f32 = 1e3;
+
+/// Largest trace of the covariance
+///
+/// Without excitation, e.g. with the device at rest, forgetting inflates `P` along the
+/// unobserved directions by `1 / forgetting` per sample until it overflows. Scaling it back
+/// keeps it at the level of no information.
+const MAX_TRACE: f32 = 4.0 * INITIAL_COVARIANCE;
+
+/// Largest offset variance, in normalized units per unit residual variance, up to which the
+/// offset counts as observed in every direction
+///
+/// This measures the excitation alone: at rest the residual falls to about zero and would
+/// make any offset look certain, while the variance along the unobserved directions stays
+/// near [`INITIAL_COVARIANCE`].
+const EXCITED_VARIANCE: f32 = 0.1;
+
+/// Weight of a new innovation in the residual variance average
+const RESIDUAL_ALPHA: f32 = 0.01;
+
+/// Incremental hard-iron offset estimator
+#[derive(Debug, Clone)]
+pub struct HardIronEstimator {
+    config: OnlineConfig,
+    /// Normalization of the field, taken from the first sample
+    scale: f32,
+    /// `[bx, by, bz, k]` in normalized units
+    theta: [f32; 4],
+    p: [[f32; 4]; 4],
+    residual_var: f32,
+    samples: u32,
+}
+
+impl Default for HardIronEstimator {
+    fn default() -> Self {
+        Self::new(OnlineConfig::default())
+    }
+}
+
+impl HardIronEstimator {
+    pub fn new(config: OnlineConfig) -> Self {
+        let mut p = [[0.0; 4]; 4];
+        for (i, row) in p.iter_mut().enumerate() {
+            row[i] = INITIAL_COVARIANCE;
+        }
+        Self {
+            config,
+            scale: 0.0,
+            theta: [0.0; 4],
+            p,
+            residual_var: 0.0,
+            samples: 0,
+        }
+    }
+
+    /// Forget everything learned so far
+    pub fn reset(&mut self) {
+        *self = Self::new(self.config);
+    }
+
+    /// Feed a sample, samples flagged invalid by the sensor are skipped
+    pub fn update(&mut self, sample: &Sample) {
+        if !sample.is_invalid() {
+            self.update_field(sample.field);
+        }
+    }
+
+    /// Feed a raw field vector in LSB
+    pub fn update_field(&mut self, field: [i32; 3]) {
+        if self.scale == 0.0 {
+            let [x, y, z] = field.map(|h| h as f32);
+            let norm = libm::sqrtf(x * x + y * y + z * z);
+            if norm == 0.0 {
+                return;
+            }
+            self.scale = norm;
+        }
+
+        let u = field.map(|h| h as f32 / self.scale);
+        let phi = [2.0 * u[0], 2.0 * u[1], 2.0 * u[2], 1.0];
+        let y = u[0] * u[0] + u[1] * u[1] + u[2] * u[2];
+        let lambda = self.config.forgetting;
+
+        let mut p_phi = [0.0f32; 4];
+        for (pp, row) in p_phi.iter_mut().zip(&self.p) {
+            *pp = dot(row, &phi);
+        }
+        let denom = lambda + dot(&phi, &p_phi);
+        let gain = p_phi.map(|v| v / denom);
+        let innovation = y - dot(&phi, &self.theta);
+
+        for (t, g) in self.theta.iter_mut().zip(gain) {
+            *t += g * innovation;
+        }
+        // P is symmetric, so p_phi doubles as phiᵀ P
+        for (row, g) in self.p.iter_mut().zip(gain) {
+            for (v, pp) in row.iter_mut().zip(p_phi) {
+                *v = (*v - g * pp) / lambda;
+            }
+        }
+        // Keep rounding errors from breaking the symmetry
+        for (i, j) in [(0, 1), (0, 2), (0, 3), (1, 2), (1, 3), (2, 3)] {
+            let v = 0.5 * (self.p[i][j] + self.p[j][i]);
+            self.p[i][j] = v;
+            self.p[j][i] = v;
+        }
+        let trace = self.p[0][0] + self.p[1][1] + self.p[2][2] + self.p[3][3];
+        if trace > MAX_TRACE {
+            let k = MAX_TRACE / trace;
+            for v in self.p.iter_mut().flatten() {
+                *v *= k;
+            }
+        }
+
+        self.residual_var += RESIDUAL_ALPHA * (innovation * innovation - self.residual_var);
+        self.samples = self.samples.saturating_add(1);
+    }
+
+    /// Number of samples fed so far
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    /// Hard-iron offset estimate X, Y, Z in LSB
+    pub fn offset(&self) -> [f32; 3] {
+        [0, 1, 2].map(|i| self.theta[i] * self.scale)
+    }
+
+    /// Field strength estimate in LSB
+    pub fn radius(&self) -> f32 {
+        let [bx, by, bz, k] = self.theta;
+        libm::sqrtf((k + bx * bx + by * by + bz * bz).max(0.0)) * self.scale
+    }
+
+    /// Largest eigenvalue of the offset block of `P`, the variance along the least excited
+    /// direction in normalized units per unit residual variance
+    fn worst_variance(&self) -> f32 {
+        let block = [0, 1, 2].map(|i| [0, 1, 2].map(|j| self.p[i][j] as f64));
+        math::sym_eigen3(block).0[2] as f32
+    }
+
+    /// One-sigma uncertainty of the worst observed offset axis, in LSB
+    pub fn uncertainty(&self) -> f32 {
+        libm::sqrtf(self.worst_variance() * self.residual_var) * self.scale
+    }
+
+    /// Whether the device has been rotated through enough directions to observe the offset
+    /// along all three axes
+    pub fn is_excited(&self) -> bool {
+        self.worst_variance() <= EXCITED_VARIANCE
+    }
+
+    /// Confidence from 0 (no information) to 1, relative to the field strength
+    ///
+    /// Scaled down while the offset is not observed in every direction.
+    pub fn confidence(&self) -> f32 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+        let radius = self.radius();
+        if radius <= 0.0 {
+            return 0.0;
+        }
+        let excitation = (EXCITED_VARIANCE / self.worst_variance()).min(1.0);
+        ((1.0 - self.uncertainty() / radius) * excitation).clamp(0.0, 1.0)
+    }
+
+    /// Whether the estimate has settled
+    ///
+    /// Requires [`HardIronEstimator::is_excited`], the uncertainty alone is no evidence since
+    /// it shrinks with the residual when the device does not move.
+    pub fn is_converged(&self) -> bool {
+        self.samples >= self.config.min_samples
+            && self.is_excited()
+            && self.uncertainty() <= self.config.converged_uncertainty
+    }
+
+    /// Current estimate as a calibration with identity soft-iron matrix
+    pub fn calibration(&self) -> Calibration {
+        Calibration {
+            offset: self.offset(),
+            ..Calibration::IDENTITY
+        }
+    }
+}
+
+fn dot(a: &[f32; 4], b: &[f32; 4]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_converges() {
+        let offset = [3000.0f32, -1500.0, 800.0];
+        let radius = 5000.0f32;
+        let mut est = HardIronEstimator::default();
+        assert!(!est.is_converged());
+
+        // Slow tumbling motion with a little deterministic noise
+        for n in 0..3000 {
+            let t = n as f32 * 0.01;
+            let theta = 1.3 * t;
+            let phi = 0.7 * t;
+            let noise = ((n * 7919) % 13) as f32 - 6.0;
+            let field = [
+                offset[0] + radius * libm::sinf(theta) * libm::cosf(phi) + noise,
+                offset[1] + radius * libm::sinf(theta) * libm::sinf(phi) - noise,
+                offset[2] + radius * libm::cosf(theta),
+            ];
+            est.update_field(field.map(|v| v as i32));
+        }
+
+        let b = est.offset();
+        for i in 0..3 {
+            assert!((b[i] - offset[i]).abs() < 20.0, "{:?}", b);
+        }
+        assert!((est.radius() - radius).abs() < 20.0);
+        assert!(est.is_converged());
+        assert!(est.confidence() > 0.95);
+    }
+
+    #[test]
+    fn test_stationary() {
+        let mut est = HardIronEstimator::default();
+        // Device at rest, only one direction is ever observed
+        for n in 0..100_000 {
+            let noise = ((n * 7919) % 5) - 2;
+            est.update_field([1200 + noise, -3400, 2500 - noise]);
+        }
+
+        assert!(est.p.iter().flatten().all(|v| v.is_finite()));
+        let trace: f32 = (0..4).map(|i| est.p[i][i]).sum();
+        assert!(trace <= MAX_TRACE * 1.001, "{trace}");
+        assert!(est.theta.iter().all(|v| v.is_finite()));
+        assert!(est.offset().iter().all(|v| v.is_finite()));
+        assert!(est.uncertainty().is_finite());
+        assert!(!est.is_excited());
+        assert!(!est.is_converged());
+        assert!(est.confidence() < 0.01, "{}", est.confidence());
+
+        // Turning about one axis only leaves the offset along it unobserved
+        let mut est = HardIronEstimator::default();
+        for n in 0..5000 {
+            let a = n as f32 * 0.01;
+            let field = [
+                3000.0 + 5000.0 * libm::cosf(a),
+                5000.0 * libm::sinf(a),
+                800.0,
+            ];
+            est.update_field(field.map(|v| v as i32));
+        }
+        assert!(!est.is_converged());
+    }
+}
@@ -0,0 +1,343 @@
+//! Temperature compensation of offset and sensitivity drift
+//!
+//! A [`TemperatureModel`] gives, per axis, the offset (in LSB) and the relative gain at a die
+//! temperature. The compensated field is `(h - offset(T)) / gain(T)`, so the output stays in LSB
+//! as seen at the reference temperature, ready for the hard/soft-iron [`Calibration`](super::Calibration).
+//!
+//! The temperature comes from `TMPS`, enable it with `AK09940A::with_temperature(true)`.
+
+use crate::sample::Sample;
+
+/// Offset and gain of all three axes at one temperature
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Drift {
+    /// Offset X, Y, Z in LSB
+    pub offset: [f32; 3],
+    /// Gain X, Y, Z relative to the reference temperature
+    pub gain: [f32; 3],
+}
+
+impl Drift {
+    /// No drift
+    pub const NONE: Self = Self {
+        offset: [0.0; 3],
+        gain: [1.0; 3],
+    };
+}
+
+/// Per-axis temperature drift coefficients
+pub trait TemperatureModel {
+    /// Drift at a die temperature in 0.001 Celsius
+    fn drift(&self, milli_celsius: i32) -> Drift;
+
+    /// Compensate a raw field vector in LSB measured at `milli_celsius`
+    fn compensate_raw(&self, field: [i32; 3], milli_celsius: i32) -> [f32; 3] {
+        let drift = self.drift(milli_celsius);
+        [0, 1, 2].map(|i| (field[i] as f32 - drift.offset[i]) / drift.gain[i])
+    }
+
+    /// Compensate a sample using its own `TMPS`
+    fn compensate(&self, sample: &Sample) -> [f32; 3] {
+        self.compensate_raw(sample.field, sample.milli_celsius())
+    }
+}
+
+/// Drift linear in temperature around a reference temperature
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearModel {
+    /// Temperature at which offset and gain drift are zero, in 0.001 Celsius
+    pub reference_milli_celsius: i32,
+    /// Offset drift X, Y, Z in LSB per Celsius
+    pub offset_slope: [f32; 3],
+    /// Relative gain drift X, Y, Z per Celsius (e.g. 100 ppm/°C is 1e-4)
+    pub gain_slope: [f32; 3],
+}
+
+impl TemperatureModel for LinearModel {
+    fn drift(&self, milli_celsius: i32) -> Drift {
+        let dt = (milli_celsius - self.reference_milli_celsius) as f32 / 1000.0;
+        Drift {
+            offset: self.offset_slope.map(|s| s * dt),
+            gain: self.gain_slope.map(|s| 1.0 + s * dt),
+        }
+    }
+}
+
+/// One point of a [`PiecewiseModel`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Breakpoint {
+    /// Temperature in 0.001 Celsius
+    pub milli_celsius: i32,
+    pub drift: Drift,
+}
+
+/// Drift interpolated linearly between `N` breakpoints, held constant outside them
+///
+/// The breakpoints must be sorted by temperature.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PiecewiseModel<const N: usize> {
+    pub breakpoints: [Breakpoint; N],
+}
+
+impl<const N: usize> TemperatureModel for PiecewiseModel<N> {
+    fn drift(&self, milli_celsius: i32) -> Drift {
+        let points = &self.breakpoints;
+        let Some(first) = points.first() else {
+            return Drift::NONE;
+        };
+        if milli_celsius <= first.milli_celsius {
+            return first.drift;
+        }
+        for pair in points.windows(2) {
+            let (lo, hi) = (&pair[0], &pair[1]);
+            if milli_celsius <= hi.milli_celsius {
+                let span = (hi.milli_celsius - lo.milli_celsius) as f32;
+                let t = if span > 0.0 {
+                    (milli_celsius - lo.milli_celsius) as f32 / span
+                } else {
+                    1.0
+                };
+                let lerp = |a: [f32; 3], b: [f32; 3]| [0, 1, 2].map(|i| a[i] + (b[i] - a[i]) * t);
+                return Drift {
+                    offset: lerp(lo.drift.offset, hi.drift.offset),
+                    gain: lerp(lo.drift.gain, hi.drift.gain),
+                };
+            }
+        }
+        points[N - 1].drift
+    }
+}
+
+/// Reason a temperature sweep could not be turned into coefficients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SweepError {
+    /// Not enough valid samples
+    TooFewSamples,
+    /// The sweep does not span enough temperature to fit a slope
+    TemperatureRange,
+    /// The two orientations do not differ in field, gain drift is not observable
+    SameOrientation,
+}
+
+/// Minimum temperature span of a sweep, in 0.001 Celsius
+const MIN_SPAN_MILLI_CELSIUS: i32 = 5000;
+
+/// Least-squares line of each axis against temperature
+struct AxisFit {
+    /// Value at the reference temperature, LSB
+    intercept: [f32; 3],
+    /// LSB per Celsius
+    slope: [f32; 3],
+}
+
+fn fit_axes(samples: &[Sample], reference_milli_celsius: i32) -> Result<AxisFit, SweepError> {
+    let valid = || samples.iter().filter(|s| !s.is_invalid());
+    let n = valid().count();
+    if n < 2 {
+        return Err(SweepError::TooFewSamples);
+    }
+    let (min, max) = valid().fold((i32::MAX, i32::MIN), |(lo, hi), s| {
+        (lo.min(s.milli_celsius()), hi.max(s.milli_celsius()))
+    });
+    if max - min < MIN_SPAN_MILLI_CELSIUS {
+        return Err(SweepError::TemperatureRange);
+    }
+
+    // Accumulate in f64, the fields are up to 24 bits
+    let n = n as f64;
+    let dt = |s: &Sample| (s.milli_celsius() - reference_milli_celsius) as f64 / 1000.0;
+    let mean_t = valid().map(dt).sum::<f64>() / n;
+    let mut mean_h = [0.0f64; 3];
+    for s in valid() {
+        for (m, h) in mean_h.iter_mut().zip(s.field) {
+            *m += h as f64 / n;
+        }
+    }
+    let mut stt = 0.0f64;
+    let mut sth = [0.0f64; 3];
+    for s in valid() {
+        let t = dt(s) - mean_t;
+        stt += t * t;
+        for i in 0..3 {
+            sth[i] += t * (s.field[i] as f64 - mean_h[i]);
+        }
+    }
+    let slope = sth.map(|v| v / stt);
+    Ok(AxisFit {
+        intercept: [0, 1, 2].map(|i| (mean_h[i] - slope[i] * mean_t) as f32),
+        slope: slope.map(|v| v as f32),
+    })
+}
+
+/// Derive the offset drift from a sweep recorded with the device at rest
+///
+/// With a single orientation offset and gain drift cannot be told apart, all drift is
+/// attributed to the offset and the gain slope is left at zero.
+pub fn derive_linear(
+    samples: &[Sample],
+    reference_milli_celsius: i32,
+) -> Result<LinearModel, SweepError> {
+    let fit = fit_axes(samples, reference_milli_celsius)?;
+    Ok(LinearModel {
+        reference_milli_celsius,
+        offset_slope: fit.slope,
+        gain_slope: [0.0; 3],
+    })
+}
+
+/// Derive offset and gain drift from two sweeps at rest, the second one with the device
+/// turned so that every axis sees the opposite field (e.g. rotated 180° about a diagonal)
+///
+/// The offset drift is the common part of the two sweeps, the gain drift the differential part.
+pub fn derive_linear_two_orientations(
+    first: &[Sample],
+    opposite: &[Sample],
+    reference_milli_celsius: i32,
+) -> Result<LinearModel, SweepError> {
+    let a = fit_axes(first, reference_milli_celsius)?;
+    let b = fit_axes(opposite, reference_milli_celsius)?;
+
+    let field = [0, 1, 2].map(|i| (a.intercept[i] - b.intercept[i]) / 2.0);
+    if field.iter().any(|f| f.abs() < 1.0) {
+        return Err(SweepError::SameOrientation);
+    }
+    let gain_slope = [0, 1, 2].map(|i| (a.slope[i] - b.slope[i]) / 2.0 / field[i]);
+    Ok(LinearModel {
+        reference_milli_celsius,
+        offset_slope: [0, 1, 2].map(|i| (a.slope[i] + b.slope[i]) / 2.0),
+        gain_slope,
+    })
+}
+
+/// Derive a piecewise offset table from a sweep recorded with the device at rest
+///
+/// The temperature range of the sweep is split into `N` equal bins (`N >= 2`), each breakpoint
+/// holds the mean offset of its bin relative to the value at the reference temperature.
+pub fn derive_piecewise<const N: usize>(
+    samples: &[Sample],
+    reference_milli_celsius: i32,
+) -> Result<PiecewiseModel<N>, SweepError> {
+    // The line fit checks the sample count and range and provides the reference value
+    let fit = fit_axes(samples, reference_milli_celsius)?;
+    if N < 2 {
+        return Err(SweepError::TooFewSamples);
+    }
+
+    let valid = || samples.iter().filter(|s| !s.is_invalid());
+    let (min, max) = valid().fold((i32::MAX, i32::MIN), |(lo, hi), s| {
+        (lo.min(s.milli_celsius()), hi.max(s.milli_celsius()))
+    });
+    let width = (max - min) as f32 / N as f32;
+
+    let mut sums = [[0.0f64; 3]; N];
+    let mut temps = [0.0f64; N];
+    let mut counts = [0u32; N];
+    for s in valid() {
+        let bin = (((s.milli_celsius() - min) as f32 / width) as usize).min(N - 1);
+        for (acc, h) in sums[bin].iter_mut().zip(s.field) {
+            *acc += h as f64;
+        }
+        temps[bin] += s.milli_celsius() as f64;
+        counts[bin] += 1;
+    }
+    if counts.contains(&0) {
+        return Err(SweepError::TooFewSamples);
+    }
+
+    let breakpoints = core::array::from_fn(|bin| {
+        let n = counts[bin] as f64;
+        Breakpoint {
+            milli_celsius: (temps[bin] / n) as i32,
+            drift: Drift {
+                offset: [0, 1, 2].map(|i| (sums[bin][i] / n) as f32 - fit.intercept[i]),
+                gain: [1.0; 3],
+            },
+        }
+    });
+    Ok(PiecewiseModel { breakpoints })
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::ll::reg::{ST1, ST2, TMPS};
+    use std::vec::Vec;
+
+    /// A sample at a die temperature, `TMPS = (30 - T) * 1.7` from the datasheet
+    fn sample(field: [f32; 3], celsius: f32) -> Sample {
+        let tmps = libm::roundf((30.0 - celsius) * 1.7) as i8;
+        Sample {
+            st1: ST1::new_with_raw_value(0x01),
+            field: field.map(|v| v as i32),
+            tmps: TMPS::new_with_raw_value(tmps as u8),
+            st2: ST2::new_with_raw_value(0x00),
+        }
+    }
+
+    /// Sweep from -20 to +60 °C in 0.5 °C steps of a sensor with the given drift
+    fn sweep(field: [f32; 3], offset_slope: [f32; 3], gain_slope: [f32; 3]) -> Vec<Sample> {
+        (-40..=120)
+            .map(|step| {
+                let celsius = step as f32 / 2.0;
+                let dt = celsius - 25.0;
+                let h =
+                    [0, 1, 2].map(|i| field[i] * (1.0 + gain_slope[i] * dt) + offset_slope[i] * dt);
+                sample(h, celsius)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_linear() {
+        let field = [20000.0, -15000.0, 40000.0];
+        let offset_slope = [3.0, -1.5, 0.5];
+        let gain_slope = [2e-4, -1e-4, 5e-5];
+        let up = sweep(field, offset_slope, gain_slope);
+        let down = sweep(field.map(|v| -v), offset_slope, gain_slope);
+
+        let model = derive_linear_two_orientations(&up, &down, 25000).unwrap();
+        for i in 0..3 {
+            assert!((model.offset_slope[i] - offset_slope[i]).abs() < 0.1);
+            assert!((model.gain_slope[i] - gain_slope[i]).abs() < 2e-6);
+        }
+        for s in &up {
+            let h = model.compensate(s);
+            for i in 0..3 {
+                assert!((h[i] - field[i]).abs() < 3.0);
+            }
+        }
+
+        assert_eq!(
+            derive_linear_two_orientations(&up, &up, 25000),
+            Err(SweepError::SameOrientation)
+        );
+        assert_eq!(
+            derive_linear(&up[..3], 25000),
+            Err(SweepError::TemperatureRange)
+        );
+    }
+
+    #[test]
+    fn test_piecewise() {
+        let field = [1000.0, 2000.0, -3000.0];
+        let offset_slope = [4.0, 0.0, -2.0];
+        let samples = sweep(field, offset_slope, [0.0; 3]);
+
+        let model: PiecewiseModel<8> = derive_piecewise(&samples, 25000).unwrap();
+        assert!(model
+            .breakpoints
+            .windows(2)
+            .all(|p| p[0].milli_celsius < p[1].milli_celsius));
+        let linear = derive_linear(&samples, 25000).unwrap();
+        for s in &samples {
+            let a = model.compensate(s);
+            let b = linear.compensate(s);
+            for i in 0..3 {
+                // 10 °C bins, off by at most half a bin times the slope at the ends
+                assert!((a[i] - b[i]).abs() < 21.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Oversampling and decimation
+//!
+//! Runs the sensor at a high output data rate and averages down by an integer ratio `R`.
+//! A [`Decimator`] is a cascaded integrator-comb (CIC) filter of order 1 to
+//! [`MAX_ORDER`], order 1 being a plain boxcar average, optionally followed by a 3-tap FIR
+//! that compensates the CIC passband droop.
+//!
+//! Everything runs in integers: the CIC in wrapping `i64`, the output in LSB with
+//! [`FRAC_BITS`] fractional bits, so averaging gains resolution instead of losing the 24-bit
+//! input precision. Each output carries the [`Status`] of all input samples it depends on,
+//! a single overflowed input marks every output it reaches.
+
+use crate::sample::Sample;
+
+/// Highest CIC order
+pub const MAX_ORDER: usize = 4;
+
+/// Fractional bits of [`Decimated::field`]
+pub const FRAC_BITS: u32 = 16;
+
+/// Fractional bits of the compensator coefficients
+const COEFF_BITS: u32 = 14;
+
+/// Largest CIC gain `R^order`, so that 24-bit input plus gain fits in an `i64`
+const MAX_GAIN: u64 = 1 << 39;
+
+/// Number of output blocks a flag can reach: CIC order plus compensator taps
+const HISTORY: usize = MAX_ORDER + 2;
+
+/// Invalid decimator settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The ratio must be at least 1
+    Ratio,
+    /// The order must be from 1 to [`MAX_ORDER`]
+    Order,
+    /// `ratio^order` exceeds 2^39
+    Gain,
+}
+
+/// Flags collected from the input samples behind an output
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Status {
+    /// Some input had `ST2.INV` set, magnetic sensor overflow
+    pub overflow: bool,
+    /// Some input had `ST2.DOR` set, a frame was overwritten before it was read
+    pub data_overrun: bool,
+    /// The `ST1` frame numbers skipped, frames are missing from the input
+    pub frames_lost: bool,
+    /// The filter has not seen enough input since the start or reset yet
+    pub settling: bool,
+}
+
+impl Status {
+    /// No flag set
+    pub fn is_clean(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn merge(self, other: Self) -> Self {
+        Self {
+            overflow: self.overflow || other.overflow,
+            data_overrun: self.data_overrun || other.data_overrun,
+            frames_lost: self.frames_lost || other.frames_lost,
+            settling: self.settling || other.settling,
+        }
+    }
+}
+
+/// One output sample
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decimated {
+    /// Field X, Y, Z in LSB with [`FRAC_BITS`] fractional bits
+    pub field: [i64; 3],
+    pub status: Status,
+}
+
+impl Decimated {
+    /// Field in LSB
+    pub fn field_lsb(&self) -> [f64; 3] {
+        self.field.map(|v| v as f64 / (1u64 << FRAC_BITS) as f64)
+    }
+}
+
+/// CIC decimator with optional droop compensation, see the [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct Decimator {
+    ratio: u32,
+    order: usize,
+    gain: i64,
+    /// Compensator `[-a, 1 + 2a, -a]`, `a` in Q14, `None` without compensation
+    compensation: Option<i64>,
+
+    integrators: [[i64; 3]; MAX_ORDER],
+    combs: [[i64; 3]; MAX_ORDER],
+    /// Last CIC outputs in Q16, newest first
+    taps: [[i64; 3]; 3],
+    /// Input samples in the current block
+    count: u32,
+    block: Status,
+    /// Status of the last blocks, newest first
+    history: [Status; HISTORY],
+    /// Outputs produced since the reset, saturating
+    outputs: usize,
+    last_frame: Option<u8>,
+}
+
+impl Decimator {
+    /// Average blocks of `ratio` samples
+    pub fn boxcar(ratio: u32) -> Result<Self, ConfigError> {
+        Self::cic(ratio, 1, false)
+    }
+
+    /// CIC of `order`, decimating by `ratio`, with the droop compensator if `compensate`
+    pub fn cic(ratio: u32, order: u8, compensate: bool) -> Result<Self, ConfigError> {
+        if ratio == 0 {
+            return Err(ConfigError::Ratio);
+        }
+        let order = order as usize;
+        if !(1..=MAX_ORDER).contains(&order) {
+            return Err(ConfigError::Order);
+        }
+        let gain = (ratio as u64)
+            .checked_pow(order as u32)
+            .filter(|&g| g <= MAX_GAIN)
+            .ok_or(ConfigError::Gain)?;
+
+        // The CIC droops by about order * (1 - 1/R²) * ω² / 24 at output frequency ω,
+        // 1 + 2a - 2a cos ω ≈ 1 + a ω² undoes it
+        let r2 = (ratio as f64) * (ratio as f64);
+        let a = order as f64 * (1.0 - 1.0 / r2) / 24.0;
+        let compensation = compensate.then(|| libm::round(a * (1 << COEFF_BITS) as f64) as i64);
+
+        Ok(Self {
+            ratio,
+            order,
+            gain: gain as i64,
+            compensation,
+            integrators: [[0; 3]; MAX_ORDER],
+            combs: [[0; 3]; MAX_ORDER],
+            taps: [[0; 3]; 3],
+            count: 0,
+            block: Status::default(),
+            history: [Status::default(); HISTORY],
+            outputs: 0,
+            last_frame: None,
+        })
+    }
+
+    pub fn ratio(&self) -> u32 {
+        self.ratio
+    }
+
+    /// Clear the filter state, e.g. after a mode change
+    pub fn reset(&mut self) {
+        self.integrators = [[0; 3]; MAX_ORDER];
+        self.combs = [[0; 3]; MAX_ORDER];
+        self.taps = [[0; 3]; 3];
+        self.count = 0;
+        self.block = Status::default();
+        self.history = [Status::default(); HISTORY];
+        self.outputs = 0;
+        self.last_frame = None;
+    }
+
+    /// Feed one sample, returns an output every `ratio` samples
+    pub fn push(&mut self, sample: &Sample) -> Option<Decimated> {
+        let frame = sample.st1.frame_number().value();
+        if let Some(last) = self.last_frame {
+            if frame != (last + 1) & 0x0F {
+                self.block.frames_lost = true;
+            }
+        }
+        self.last_frame = Some(frame);
+        self.block.overflow |= sample.st2.invalid_data();
+        self.block.data_overrun |= sample.st2.data_overrun();
+
+        let mut acc = sample.field.map(|v| v as i64);
+        for stage in &mut self.integrators[..self.order] {
+            for (s, a) in stage.iter_mut().zip(&mut acc) {
+                *s = s.wrapping_add(*a);
+                *a = *s;
+            }
+        }
+
+        self.count += 1;
+        if self.count < self.ratio {
+            return None;
+        }
+        self.count = 0;
+
+        for stage in &mut self.combs[..self.order] {
+            for (prev, a) in stage.iter_mut().zip(&mut acc) {
+                let x = *a;
+                *a = x.wrapping_sub(*prev);
+                *prev = x;
+            }
+        }
+        let cic = acc.map(|v| scale(v, self.gain));
+
+        self.history.copy_within(0..HISTORY - 1, 1);
+        self.history[0] = core::mem::take(&mut self.block);
+        self.outputs = self.outputs.saturating_add(1);
+
+        let (field, reach) = match self.compensation {
+            None => (cic, self.order),
+            Some(a) => {
+                self.taps.copy_within(0..2, 1);
+                self.taps[0] = cic;
+                let t = &self.taps;
+                let field = [0, 1, 2].map(|i| {
+                    let acc = (t[1][i] * ((1 << COEFF_BITS) + 2 * a)) - (t[0][i] + t[2][i]) * a;
+                    round_shift(acc, COEFF_BITS)
+                });
+                (field, self.order + 2)
+            }
+        };
+
+        let mut status = self.history[..reach]
+            .iter()
+            .fold(Status::default(), |s, h| s.merge(*h));
+        status.settling = self.outputs < reach;
+        Some(Decimated { field, status })
+    }
+
+    /// Feed a FIFO burst, `emit` is called with every output
+    pub fn push_burst(&mut self, samples: &[Sample], mut emit: impl FnMut(Decimated)) {
+        for sample in samples {
+            if let Some(out) = self.push(sample) {
+                emit(out);
+            }
+        }
+    }
+}
+
+/// `v / gain` in Q16, rounded, without overflowing for `|v|` up to 2^63
+fn scale(v: i64, gain: i64) -> i64 {
+    let q = v.div_euclid(gain);
+    let r = v.rem_euclid(gain);
+    (q << FRAC_BITS) + ((r << FRAC_BITS) + gain / 2) / gain
+}
+
+fn round_shift(v: i64, bits: u32) -> i64 {
+    (v + (1 << (bits - 1))) >> bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ll::reg::{ST1, ST2, TMPS};
+
+    fn sample(frame: u8, field: [i32; 3], st2: u8) -> Sample {
+        Sample {
+            st1: ST1::new_with_raw_value(((frame & 0x0F) << 1) | 0x01),
+            field,
+            tmps: TMPS::new_with_raw_value(0),
+            st2: ST2::new_with_raw_value(st2),
+        }
+    }
+
+    const ONE: i64 = 1 << FRAC_BITS;
+
+    #[test]
+    fn test_boxcar() {
+        assert_eq!(Decimator::boxcar(0).err(), Some(ConfigError::Ratio));
+        assert_eq!(Decimator::cic(4, 5, false).err(), Some(ConfigError::Order));
+        assert_eq!(
+            Decimator::cic(1 << 20, 2, false).err(),
+            Some(ConfigError::Gain)
+        );
+
+        let mut dec = Decimator::boxcar(4).unwrap();
+        let burst = [
+            sample(0, [1, -1, 8_388_607], 0),
+            sample(1, [2, -2, 8_388_607], 0),
+            sample(2, [2, -2, 8_388_607], 0),
+            sample(3, [2, -2, 8_388_606], 0),
+        ];
+        let mut out = None;
+        dec.push_burst(&burst, |d| out = Some(d));
+        let out = out.unwrap();
+        // 7/4 keeps its fraction
+        assert_eq!(
+            out.field,
+            [7 * ONE / 4, -7 * ONE / 4, 8_388_607 * ONE - ONE / 4]
+        );
+        assert!(out.status.is_clean());
+    }
+
+    #[test]
+    fn test_cic_status() {
+        let mut dec = Decimator::cic(8, 3, true).unwrap();
+        let mut outputs = [Decimated {
+            field: [0; 3],
+            status: Status::default(),
+        }; 12];
+        let mut n = 0;
+        for i in 0..96u32 {
+            // Overflow in block 6
+            let st2 = if i == 50 { 0x02 } else { 0x00 };
+            if let Some(d) = dec.push(&sample(i as u8, [-123_456, 0, 5_000_000], st2)) {
+                outputs[n] = d;
+                n += 1;
+            }
+        }
+        assert_eq!(n, 12);
+
+        // Unity gain at DC once settled, and the compensator sums to one
+        for d in &outputs[4..] {
+            assert_eq!(d.field, [-123_456 * ONE, 0, 5_000_000 * ONE]);
+        }
+        // Order 3 plus 2 compensator taps
+        for (i, d) in outputs.iter().enumerate() {
+            assert_eq!(d.status.settling, i < 4);
+            assert_eq!(d.status.overflow, (6..11).contains(&i), "{i}");
+        }
+
+        // A skipped frame number marks the output
+        let mut dec = Decimator::boxcar(2).unwrap();
+        assert_eq!(dec.push(&sample(0, [0; 3], 0)), None);
+        assert!(dec.push(&sample(2, [0; 3], 0)).unwrap().status.frames_lost);
+        assert_eq!(dec.push(&sample(3, [0; 3], 0)), None);
+        assert!(dec.push(&sample(4, [0; 3], 0)).unwrap().status.is_clean());
+    }
+}
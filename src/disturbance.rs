@@ -0,0 +1,238 @@
+//! Magnetic disturbance detection
+//!
+//! Compares the magnitude and, given the gravity vector, the inclination of the calibrated field
+//! against a [`Reference`], either supplied by the user or taken from the magnetic model.
+//! Nearby steel or motor currents change both long before the heading error becomes obvious.
+//!
+//! The detector switches between [`State::Clean`] and [`State::Disturbed`] with hysteresis and
+//! a dwell time, so fusion code can gate magnetometer updates on it without chattering.
+
+use core::f32::consts::PI;
+
+/// Undisturbed field at the current location
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Reference {
+    /// Total intensity in LSB
+    pub total: f32,
+    /// Inclination in radians, positive down
+    pub inclination: f32,
+}
+
+#[cfg(feature = "wmm")]
+impl From<crate::wmm::Field> for Reference {
+    fn from(field: crate::wmm::Field) -> Self {
+        Self {
+            total: field.total_lsb(),
+            inclination: field.inclination.to_radians(),
+        }
+    }
+}
+
+/// Tuning of the [`DisturbanceDetector`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DisturbanceConfig {
+    /// Relative magnitude error that counts as disturbed, e.g. 0.1 for 10 %
+    pub magnitude_tolerance: f32,
+    /// Inclination error in radians that counts as disturbed
+    pub inclination_tolerance: f32,
+    /// Fraction of the tolerances the errors must fall below to count as clean again
+    pub hysteresis: f32,
+    /// Consecutive disturbed samples before switching to disturbed
+    pub enter_samples: u16,
+    /// Consecutive clean samples before switching back to clean
+    pub exit_samples: u16,
+}
+
+impl Default for DisturbanceConfig {
+    /// 10 % and 5°, clean again below half of that, after 3 and 20 samples
+    fn default() -> Self {
+        Self {
+            magnitude_tolerance: 0.1,
+            inclination_tolerance: 5.0 * PI / 180.0,
+            hysteresis: 0.5,
+            enter_samples: 3,
+            exit_samples: 20,
+        }
+    }
+}
+
+/// Whether the field can be trusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Clean,
+    Disturbed,
+}
+
+/// Result of one [`DisturbanceDetector::update`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Assessment {
+    pub state: State,
+    /// Confidence in the field from 0 to 1, 0 at or beyond the tolerances
+    pub confidence: f32,
+    /// Relative magnitude error, signed
+    pub magnitude_error: f32,
+    /// Inclination error in radians, signed, `None` without a gravity vector
+    pub inclination_error: Option<f32>,
+}
+
+/// Disturbed/clean state machine with hysteresis
+#[derive(Debug, Clone)]
+pub struct DisturbanceDetector {
+    config: DisturbanceConfig,
+    reference: Reference,
+    state: State,
+    /// Consecutive samples disagreeing with the current state
+    count: u16,
+}
+
+impl DisturbanceDetector {
+    /// Create a detector, it starts out clean
+    pub fn new(reference: Reference, config: DisturbanceConfig) -> Self {
+        Self {
+            config,
+            reference,
+            state: State::Clean,
+            count: 0,
+        }
+    }
+
+    /// Replace the reference, e.g. after moving to a new location
+    pub fn set_reference(&mut self, reference: Reference) {
+        self.reference = reference;
+    }
+
+    pub fn reference(&self) -> &Reference {
+        &self.reference
+    }
+
+    /// Current state
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Return to clean
+    pub fn reset(&mut self) {
+        self.state = State::Clean;
+        self.count = 0;
+    }
+
+    /// Assess a calibrated field in LSB
+    ///
+    /// `gravity` points down in the same frame as `field`, its scale does not matter.
+    /// Without it only the magnitude is checked.
+    pub fn update(&mut self, field: [f32; 3], gravity: Option<[f32; 3]>) -> Assessment {
+        let total = norm(&field);
+        let magnitude_error = if self.reference.total > 0.0 {
+            total / self.reference.total - 1.0
+        } else {
+            0.0
+        };
+        let inclination_error = gravity.and_then(|g| {
+            let g_norm = norm(&g);
+            if g_norm == 0.0 || total == 0.0 {
+                return None;
+            }
+            let down = (field[0] * g[0] + field[1] * g[1] + field[2] * g[2]) / (g_norm * total);
+            Some(libm::asinf(down.clamp(-1.0, 1.0)) - self.reference.inclination)
+        });
+
+        // 1 at the tolerance on the worse of the two
+        let mut score = libm::fabsf(magnitude_error) / self.config.magnitude_tolerance;
+        if let Some(e) = inclination_error {
+            score = score.max(libm::fabsf(e) / self.config.inclination_tolerance);
+        }
+        if !score.is_finite() {
+            score = f32::INFINITY;
+        }
+
+        let (against, needed) = match self.state {
+            State::Clean => (score > 1.0, self.config.enter_samples),
+            State::Disturbed => (score < self.config.hysteresis, self.config.exit_samples),
+        };
+        if against {
+            self.count = self.count.saturating_add(1);
+            if self.count >= needed.max(1) {
+                self.state = match self.state {
+                    State::Clean => State::Disturbed,
+                    State::Disturbed => State::Clean,
+                };
+                self.count = 0;
+            }
+        } else {
+            self.count = 0;
+        }
+
+        Assessment {
+            state: self.state,
+            confidence: (1.0 - score).clamp(0.0, 1.0),
+            magnitude_error,
+            inclination_error,
+        }
+    }
+}
+
+fn norm(v: &[f32; 3]) -> f32 {
+    libm::sqrtf(v[0] * v[0] + v[1] * v[1] + v[2] * v[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hysteresis() {
+        // 50 µT at 60° down
+        let inclination = 60.0 * PI / 180.0;
+        let reference = Reference {
+            total: 5000.0,
+            inclination,
+        };
+        let mut det = DisturbanceDetector::new(reference, DisturbanceConfig::default());
+        let clean = [
+            5000.0 * libm::cosf(inclination),
+            0.0,
+            5000.0 * libm::sinf(inclination),
+        ];
+        let gravity = Some([0.0, 0.0, 9.81]);
+
+        let a = det.update(clean, gravity);
+        assert_eq!(a.state, State::Clean);
+        assert!(a.confidence > 0.99);
+        assert!(a.inclination_error.unwrap().abs() < 1e-4);
+
+        // 20 % stronger, disturbed after 3 samples
+        let strong = clean.map(|v| v * 1.2);
+        assert_eq!(det.update(strong, gravity).state, State::Clean);
+        assert_eq!(det.update(strong, gravity).state, State::Clean);
+        let a = det.update(strong, gravity);
+        assert_eq!(a.state, State::Disturbed);
+        assert_eq!(a.confidence, 0.0);
+        assert!((a.magnitude_error - 0.2).abs() < 1e-4);
+
+        // 7 % is within the tolerance but not below the hysteresis
+        for _ in 0..50 {
+            assert_eq!(
+                det.update(clean.map(|v| v * 1.07), gravity).state,
+                State::Disturbed
+            );
+        }
+        for _ in 0..19 {
+            assert_eq!(det.update(clean, gravity).state, State::Disturbed);
+        }
+        assert_eq!(det.update(clean, gravity).state, State::Clean);
+
+        // Right magnitude, wrong inclination
+        let tilted = [5000.0, 0.0, 0.0];
+        for _ in 0..3 {
+            det.update(tilted, gravity);
+        }
+        assert_eq!(det.state(), State::Disturbed);
+        // Without gravity only the magnitude is checked
+        det.reset();
+        for _ in 0..10 {
+            let a = det.update(tilted, None);
+            assert_eq!(a.state, State::Clean);
+            assert_eq!(a.inclination_error, None);
+        }
+    }
+}
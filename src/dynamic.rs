@@ -0,0 +1,393 @@
+use duplicate::duplicate_item;
+
+/// Operation mode of the runtime-dispatched driver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Powerdown,
+    SingleShot,
+    /// Continuous mode N, N from 1..=8
+    Continuous(u8),
+    ExternalTrigger,
+}
+
+#[duplicate_item(
+    async_type           maybe_async_attr   SpiType;
+    [ non_blocking ]     [ must_be_async ]    [ embedded_hal_async ];
+    [ blocking ]         [ must_be_sync ]     [ embedded_hal ];
+)]
+pub mod async_type {
+    use super::Mode;
+    use crate::async_type::{Error, TransitionError, AK09940A};
+    use crate::ll::async_type::LL;
+    use crate::ll::NoDelay;
+    use crate::sample::Sample;
+    use crate::states::{Continuous, ExternalTrigger, Powerdown, SingleShot};
+    use crate::Measurement;
+    use maybe_async::maybe_async_attr;
+    use SpiType::delay::DelayNs;
+    use SpiType::spi::SpiDevice;
+
+    enum Inner<DEV, B>
+    where
+        DEV: SpiDevice,
+    {
+        Powerdown(AK09940A<DEV, Powerdown, B>),
+        SingleShot(AK09940A<DEV, SingleShot, B>),
+        Continuous(AK09940A<DEV, Continuous, B>, u8),
+        ExternalTrigger(AK09940A<DEV, ExternalTrigger, B>),
+    }
+
+    /// AK09940A driver with the operation mode tracked at runtime
+    ///
+    /// Wraps the typestate driver so that it can live in a single struct field
+    /// while the mode changes, e.g. from a configuration menu.
+    /// Operations that are only valid in some modes return [`Error::InvalidMode`] otherwise.
+    ///
+    /// # Cancel safety
+    ///
+    /// [`set_mode`](Self::set_mode) is not cancel-safe: the typestate transitions consume the
+    /// driver, so dropping the future while it waits on the bus drops the driver with it.
+    /// Every later call then returns [`Error::DriverLost`].
+    pub struct DynAK09940A<DEV, B = NoDelay>
+    where
+        DEV: SpiDevice,
+    {
+        // Only taken for the duration of a transition, `None` if that was cancelled
+        inner: Option<Inner<DEV, B>>,
+    }
+
+    impl<DEV> DynAK09940A<DEV>
+    where
+        DEV: SpiDevice,
+    {
+        pub fn new(dev: DEV) -> Self {
+            AK09940A::new(dev).into()
+        }
+    }
+
+    impl<DEV, B> DynAK09940A<DEV, B>
+    where
+        DEV: SpiDevice,
+        B: DelayNs,
+    {
+        /// Wrap a driver in continuous mode N
+        pub fn from_continuous(driver: AK09940A<DEV, Continuous, B>, mode: u8) -> Self {
+            Self {
+                inner: Some(Inner::Continuous(driver, mode)),
+            }
+        }
+
+        fn inner_mut(&mut self) -> Result<&mut Inner<DEV, B>, Error<DEV>> {
+            self.inner.as_mut().ok_or(Error::DriverLost)
+        }
+
+        /// Current operation mode, `None` if a cancelled mode switch dropped the driver
+        pub fn mode(&self) -> Option<Mode> {
+            Some(match self.inner.as_ref()? {
+                Inner::Powerdown(_) => Mode::Powerdown,
+                Inner::SingleShot(_) => Mode::SingleShot,
+                Inner::Continuous(_, mode) => Mode::Continuous(*mode),
+                Inner::ExternalTrigger(_) => Mode::ExternalTrigger,
+            })
+        }
+
+        /// Low level access to the sensor
+        pub fn ll(&mut self) -> Result<&mut LL<DEV, B>, Error<DEV>> {
+            Ok(match self.inner_mut()? {
+                Inner::Powerdown(d) => &mut d.dev,
+                Inner::SingleShot(d) => &mut d.dev,
+                Inner::Continuous(d, _) => &mut d.dev,
+                Inner::ExternalTrigger(d) => &mut d.dev,
+            })
+        }
+
+        /// Switch to another operation mode
+        ///
+        /// The sensor is reset first unless it is already powered down.
+        /// If the reset fails the driver stays in its current mode, if entering the new mode fails it is left powered down.
+        /// An invalid continuous mode number is rejected before touching the sensor.
+        #[maybe_async_attr]
+        pub async fn set_mode(&mut self, mode: Mode) -> Result<(), Error<DEV>> {
+            if let Mode::Continuous(m) = mode {
+                if !(1..=8).contains(&m) {
+                    return Err(Error::InvalidMode);
+                }
+            }
+
+            let inner = self.inner.take().ok_or(Error::DriverLost)?;
+            let powerdown = match inner {
+                Inner::Powerdown(d) => Ok(d),
+                Inner::SingleShot(d) => d
+                    .reset()
+                    .await
+                    .map_err(|e| Self::restore(e, Inner::SingleShot)),
+                Inner::Continuous(d, m) => d
+                    .reset()
+                    .await
+                    .map_err(|e| Self::restore(e, |d| Inner::Continuous(d, m))),
+                Inner::ExternalTrigger(d) => d
+                    .reset()
+                    .await
+                    .map_err(|e| Self::restore(e, Inner::ExternalTrigger)),
+            };
+            let powerdown = match powerdown {
+                Ok(d) => d,
+                Err((inner, error)) => {
+                    self.inner = Some(inner);
+                    return Err(error);
+                }
+            };
+
+            let result = match mode {
+                Mode::Powerdown => Ok(Inner::Powerdown(powerdown)),
+                Mode::SingleShot => powerdown.single_shot().await.map(Inner::SingleShot),
+                Mode::Continuous(m) => powerdown
+                    .continuous(m)
+                    .await
+                    .map(|d| Inner::Continuous(d, m)),
+                Mode::ExternalTrigger => powerdown
+                    .external_trigger()
+                    .await
+                    .map(Inner::ExternalTrigger),
+            };
+            match result {
+                Ok(inner) => {
+                    self.inner = Some(inner);
+                    Ok(())
+                }
+                Err(e) => {
+                    let (driver, error) = e.into_parts();
+                    self.inner = Some(Inner::Powerdown(driver));
+                    Err(error)
+                }
+            }
+        }
+
+        fn restore<State>(
+            e: TransitionError<DEV, State, B>,
+            wrap: impl FnOnce(AK09940A<DEV, State, B>) -> Inner<DEV, B>,
+        ) -> (Inner<DEV, B>, Error<DEV>) {
+            let (driver, error) = e.into_parts();
+            (wrap(driver), error)
+        }
+
+        /// Read the data from the AK09940A
+        ///
+        /// Not available in power-down mode.
+        #[maybe_async_attr]
+        pub async fn read_data(&mut self) -> Result<Measurement, Error<DEV>> {
+            match self.inner_mut()? {
+                Inner::Powerdown(_) => Err(Error::InvalidMode),
+                Inner::SingleShot(d) => d.read_data().await,
+                Inner::Continuous(d, _) => d.read_data().await,
+                Inner::ExternalTrigger(d) => d.read_data().await,
+            }
+        }
+
+        /// Read the data from the AK09940A as a [`Sample`], in the body frame
+        ///
+        /// Not available in power-down mode.
+        #[maybe_async_attr]
+        pub async fn read_sample(&mut self) -> Result<Sample, Error<DEV>> {
+            match self.inner_mut()? {
+                Inner::Powerdown(_) => Err(Error::InvalidMode),
+                Inner::SingleShot(d) => d.read_sample().await,
+                Inner::Continuous(d, _) => d.read_sample().await,
+                Inner::ExternalTrigger(d) => d.read_sample().await,
+            }
+        }
+
+        /// Start a new single-shot measurement
+        ///
+        /// Only available in single-shot mode.
+        #[maybe_async_attr]
+        pub async fn start_measurement(&mut self) -> Result<(), Error<DEV>> {
+            match self.inner_mut()? {
+                Inner::SingleShot(d) => d.start_measurement().await,
+                _ => Err(Error::InvalidMode),
+            }
+        }
+
+        /// Start a new external trigger measurement
+        ///
+        /// Only available in external trigger mode.
+        #[maybe_async_attr]
+        pub async fn start_waiting(&mut self) -> Result<(), Error<DEV>> {
+            match self.inner_mut()? {
+                Inner::ExternalTrigger(d) => d.start_waiting().await,
+                _ => Err(Error::InvalidMode),
+            }
+        }
+
+        /// Convert back into the typed driver, if powered down
+        pub fn into_powerdown(self) -> Result<AK09940A<DEV, Powerdown, B>, Self> {
+            match self.inner {
+                Some(Inner::Powerdown(d)) => Ok(d),
+                inner => Err(Self { inner }),
+            }
+        }
+
+        /// Convert back into the typed driver, if in single-shot mode
+        pub fn into_single_shot(self) -> Result<AK09940A<DEV, SingleShot, B>, Self> {
+            match self.inner {
+                Some(Inner::SingleShot(d)) => Ok(d),
+                inner => Err(Self { inner }),
+            }
+        }
+
+        /// Convert back into the typed driver and the mode number, if in continuous mode
+        pub fn into_continuous(self) -> Result<(AK09940A<DEV, Continuous, B>, u8), Self> {
+            match self.inner {
+                Some(Inner::Continuous(d, mode)) => Ok((d, mode)),
+                inner => Err(Self { inner }),
+            }
+        }
+
+        /// Convert back into the typed driver, if in external trigger mode
+        pub fn into_external_trigger(self) -> Result<AK09940A<DEV, ExternalTrigger, B>, Self> {
+            match self.inner {
+                Some(Inner::ExternalTrigger(d)) => Ok(d),
+                inner => Err(Self { inner }),
+            }
+        }
+    }
+
+    impl<DEV, B> From<AK09940A<DEV, Powerdown, B>> for DynAK09940A<DEV, B>
+    where
+        DEV: SpiDevice,
+        B: DelayNs,
+    {
+        fn from(driver: AK09940A<DEV, Powerdown, B>) -> Self {
+            Self {
+                inner: Some(Inner::Powerdown(driver)),
+            }
+        }
+    }
+
+    impl<DEV, B> From<AK09940A<DEV, SingleShot, B>> for DynAK09940A<DEV, B>
+    where
+        DEV: SpiDevice,
+        B: DelayNs,
+    {
+        fn from(driver: AK09940A<DEV, SingleShot, B>) -> Self {
+            Self {
+                inner: Some(Inner::SingleShot(driver)),
+            }
+        }
+    }
+
+    impl<DEV, B> From<AK09940A<DEV, ExternalTrigger, B>> for DynAK09940A<DEV, B>
+    where
+        DEV: SpiDevice,
+        B: DelayNs,
+    {
+        fn from(driver: AK09940A<DEV, ExternalTrigger, B>) -> Self {
+            Self {
+                inner: Some(Inner::ExternalTrigger(driver)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::blocking::DynAK09940A;
+    use super::Mode;
+    use crate::blocking::{Error, AK09940A};
+    use crate::frame::AxisMap;
+    use crate::test_util::{block, reset, transfer};
+    use core::future::Future;
+    use core::task::{Context, Waker};
+    use embedded_hal::spi::{ErrorKind, ErrorType, Operation};
+    use embedded_hal_mock::eh1::spi::Mock;
+
+    /// Never completes a transaction
+    struct Stalled;
+
+    impl ErrorType for Stalled {
+        type Error = ErrorKind;
+    }
+
+    impl embedded_hal_async::spi::SpiDevice for Stalled {
+        async fn transaction(&mut self, _: &mut [Operation<'_, u8>]) -> Result<(), ErrorKind> {
+            core::future::pending().await
+        }
+    }
+
+    #[test]
+    fn runtime_mode_checks() {
+        // Wrong company ID, the driver stays powered down
+        let expectations = reset(0x00);
+        let mut spi = Mock::new(&expectations);
+        let mut mag = DynAK09940A::new(spi.clone());
+
+        assert!(matches!(mag.read_data(), Err(Error::InvalidMode)));
+        assert!(matches!(
+            mag.set_mode(Mode::SingleShot),
+            Err(Error::InvalidWhoAmI(0x00))
+        ));
+        assert_eq!(mag.mode(), Some(Mode::Powerdown));
+        assert!(matches!(mag.start_measurement(), Err(Error::InvalidMode)));
+        assert!(mag.into_powerdown().is_ok());
+
+        spi.done();
+    }
+
+    #[test]
+    fn mounting_is_applied() {
+        let mut response = [0x00; 12];
+        response[0] = 0x01;
+        response[1] = 5;
+        response[4] = 7;
+        response[7] = 9;
+        let expectations = [
+            &reset(0x48)[..],
+            &transfer([0xB2, 0x00], [0x00, 0x00]),
+            &transfer([0x32, 0x01], [0x00, 0x00]),
+            &block(response),
+        ]
+        .concat();
+        let mut spi = Mock::new(&expectations);
+        let mut mag = DynAK09940A::from(AK09940A::new(spi.clone()).with_mounting(AxisMap::YAW_90));
+
+        assert!(matches!(mag.read_sample(), Err(Error::InvalidMode)));
+        mag.set_mode(Mode::SingleShot).unwrap();
+        // Rejected without resetting the sensor
+        assert!(matches!(
+            mag.set_mode(Mode::Continuous(9)),
+            Err(Error::InvalidMode)
+        ));
+        assert_eq!(mag.mode(), Some(Mode::SingleShot));
+        let sample = mag.read_sample().unwrap();
+        assert_eq!(sample.field, AxisMap::YAW_90.apply([5, 7, 9]));
+        assert_eq!(sample.field, [-7, 5, 9]);
+
+        spi.done();
+    }
+
+    #[test]
+    fn cancelled_mode_switch() {
+        let mut mag = super::non_blocking::DynAK09940A::new(Stalled);
+        {
+            let mut switch = core::pin::pin!(mag.set_mode(Mode::SingleShot));
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(switch.as_mut().poll(&mut cx).is_pending());
+        }
+
+        // The driver went with the dropped future, no panics from here on
+        assert_eq!(mag.mode(), None);
+        assert!(mag.ll().is_err());
+        let result = async_std::task::block_on(mag.read_data());
+        assert!(matches!(
+            result,
+            Err(crate::non_blocking::Error::DriverLost)
+        ));
+        let result = async_std::task::block_on(mag.set_mode(Mode::Powerdown));
+        assert!(matches!(
+            result,
+            Err(crate::non_blocking::Error::DriverLost)
+        ));
+    }
+}
@@ -0,0 +1,319 @@
+//! Mapping from the sensor frame to the body frame
+//!
+//! The AK09940A measures in its own X/Y/Z. [`Mounting`] describes how the chip sits on the
+//! board, either as one of the 48 signed axis permutations ([`AxisMap`], exact on the integer
+//! samples) or as a general rotation matrix. Set it with `AK09940A::with_mounting` and every
+//! `read_sample` comes out in the body frame.
+//!
+//! Both describe `h_body = R h_sensor`.
+
+/// A signed sensor axis
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ,
+}
+
+impl Axis {
+    const fn index(self) -> usize {
+        match self {
+            Axis::PosX | Axis::NegX => 0,
+            Axis::PosY | Axis::NegY => 1,
+            Axis::PosZ | Axis::NegZ => 2,
+        }
+    }
+
+    const fn is_negative(self) -> bool {
+        matches!(self, Axis::NegX | Axis::NegY | Axis::NegZ)
+    }
+
+    const fn from_parts(index: usize, negative: bool) -> Self {
+        match (index, negative) {
+            (0, false) => Axis::PosX,
+            (0, true) => Axis::NegX,
+            (1, false) => Axis::PosY,
+            (1, true) => Axis::NegY,
+            (2, false) => Axis::PosZ,
+            _ => Axis::NegZ,
+        }
+    }
+
+    /// The same axis, pointing the other way
+    pub const fn opposite(self) -> Self {
+        Self::from_parts(self.index(), !self.is_negative())
+    }
+}
+
+/// Signed permutation of the sensor axes
+///
+/// `AxisMap::new(x, y, z)` names the sensor axis that reads as body X, Y and Z.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AxisMap {
+    axes: [Axis; 3],
+}
+
+impl Default for AxisMap {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+impl AxisMap {
+    /// Body frame equals sensor frame
+    pub const IDENTITY: Self = Self::new(Axis::PosX, Axis::PosY, Axis::PosZ);
+    /// Chip rotated +90° about Z
+    pub const YAW_90: Self = Self::new(Axis::NegY, Axis::PosX, Axis::PosZ);
+    /// Chip rotated 180° about Z
+    pub const YAW_180: Self = Self::new(Axis::NegX, Axis::NegY, Axis::PosZ);
+    /// Chip rotated +270° about Z
+    pub const YAW_270: Self = Self::new(Axis::PosY, Axis::NegX, Axis::PosZ);
+    /// Chip rotated +90° about X
+    pub const ROLL_90: Self = Self::new(Axis::PosX, Axis::NegZ, Axis::PosY);
+    /// Chip rotated 180° about X, e.g. on the bottom side of the board
+    pub const ROLL_180: Self = Self::new(Axis::PosX, Axis::NegY, Axis::NegZ);
+    /// Chip rotated +270° about X
+    pub const ROLL_270: Self = Self::new(Axis::PosX, Axis::PosZ, Axis::NegY);
+    /// Chip rotated +90° about Y
+    pub const PITCH_90: Self = Self::new(Axis::PosZ, Axis::PosY, Axis::NegX);
+    /// Chip rotated 180° about Y
+    pub const PITCH_180: Self = Self::new(Axis::NegX, Axis::PosY, Axis::NegZ);
+    /// Chip rotated +270° about Y
+    pub const PITCH_270: Self = Self::new(Axis::NegZ, Axis::PosY, Axis::PosX);
+
+    /// All 24 right-angle orientations, in the order of [`AxisMap::from_xz`] over
+    /// `x` = +X, -X, +Y, -Y, +Z, -Z and then `z` in the same order
+    pub const ORIENTATIONS: [Self; 24] = {
+        const AXES: [Axis; 6] = [
+            Axis::PosX,
+            Axis::NegX,
+            Axis::PosY,
+            Axis::NegY,
+            Axis::PosZ,
+            Axis::NegZ,
+        ];
+        let mut out = [Self::IDENTITY; 24];
+        let mut n = 0;
+        let mut i = 0;
+        while i < 6 {
+            let mut j = 0;
+            while j < 6 {
+                if AXES[i].index() != AXES[j].index() {
+                    out[n] = Self::from_xz(AXES[i], AXES[j]);
+                    n += 1;
+                }
+                j += 1;
+            }
+            i += 1;
+        }
+        out
+    };
+
+    /// Map from the sensor axes read as body X, Y and Z
+    ///
+    /// Panics (at compile time in a const context) if an axis is used twice.
+    /// Mirror images are allowed, see [`AxisMap::is_rotation`].
+    pub const fn new(x: Axis, y: Axis, z: Axis) -> Self {
+        assert!(
+            x.index() != y.index() && x.index() != z.index() && y.index() != z.index(),
+            "every sensor axis must be used exactly once"
+        );
+        Self { axes: [x, y, z] }
+    }
+
+    /// Right-angle orientation from the sensor axes read as body X and body Z
+    ///
+    /// Body Y completes a right-handed frame. Panics if `x` and `z` are parallel.
+    pub const fn from_xz(x: Axis, z: Axis) -> Self {
+        assert!(x.index() != z.index(), "x and z must not be parallel");
+        // y = z × x, the missing index with the sign of the cross product
+        let y_index = 3 - x.index() - z.index();
+        let cyclic = (z.index() + 1) % 3 == x.index();
+        let negative = cyclic == (x.is_negative() != z.is_negative());
+        Self::new(x, Axis::from_parts(y_index, negative), z)
+    }
+
+    /// Sensor axes read as body X, Y and Z
+    pub const fn axes(&self) -> [Axis; 3] {
+        self.axes
+    }
+
+    /// Whether this is a proper rotation, not a mirror image
+    pub const fn is_rotation(&self) -> bool {
+        let [x, y, z] = self.axes;
+        let cyclic = (x.index() + 1) % 3 == y.index();
+        let negatives = x.is_negative() as u8 + y.is_negative() as u8 + z.is_negative() as u8;
+        cyclic == (negatives & 1 == 0)
+    }
+
+    /// The map as a matrix, `h_body = R h_sensor`
+    pub const fn matrix(&self) -> [[i8; 3]; 3] {
+        let mut m = [[0; 3]; 3];
+        let mut i = 0;
+        while i < 3 {
+            m[i][self.axes[i].index()] = if self.axes[i].is_negative() { -1 } else { 1 };
+            i += 1;
+        }
+        m
+    }
+
+    /// This map followed by `next`
+    pub const fn then(self, next: Self) -> Self {
+        let mut axes = next.axes;
+        let mut i = 0;
+        while i < 3 {
+            let inner = self.axes[next.axes[i].index()];
+            axes[i] = Axis::from_parts(
+                inner.index(),
+                inner.is_negative() != next.axes[i].is_negative(),
+            );
+            i += 1;
+        }
+        Self { axes }
+    }
+
+    /// Map from the body frame back to the sensor frame
+    pub const fn inverse(self) -> Self {
+        let mut axes = self.axes;
+        let mut i = 0;
+        while i < 3 {
+            let a = self.axes[i];
+            axes[a.index()] = Axis::from_parts(i, a.is_negative());
+            i += 1;
+        }
+        Self { axes }
+    }
+
+    /// Map a vector from the sensor frame to the body frame
+    pub fn apply(&self, v: [i32; 3]) -> [i32; 3] {
+        self.axes.map(|a| {
+            let h = v[a.index()];
+            if a.is_negative() {
+                h.wrapping_neg()
+            } else {
+                h
+            }
+        })
+    }
+
+    /// Map an `f32` vector from the sensor frame to the body frame
+    pub fn apply_f32(&self, v: [f32; 3]) -> [f32; 3] {
+        self.axes.map(|a| {
+            let h = v[a.index()];
+            if a.is_negative() {
+                -h
+            } else {
+                h
+            }
+        })
+    }
+}
+
+/// How the chip is mounted relative to the body frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mounting {
+    /// Right-angle mounting, exact
+    Axes(AxisMap),
+    /// Arbitrary mounting, row-major rotation matrix, results are rounded to the nearest LSB
+    Rotation([[f32; 3]; 3]),
+}
+
+impl Default for Mounting {
+    fn default() -> Self {
+        Mounting::Axes(AxisMap::IDENTITY)
+    }
+}
+
+impl From<AxisMap> for Mounting {
+    fn from(map: AxisMap) -> Self {
+        Mounting::Axes(map)
+    }
+}
+
+impl Mounting {
+    /// Map a raw field vector in LSB from the sensor frame to the body frame
+    pub fn apply(&self, v: [i32; 3]) -> [i32; 3] {
+        match self {
+            Mounting::Axes(map) => map.apply(v),
+            Mounting::Rotation(r) => {
+                let v = v.map(|h| h as f32);
+                r.map(|row| libm::roundf(row[0] * v[0] + row[1] * v[1] + row[2] * v[2]) as i32)
+            }
+        }
+    }
+
+    /// Map an `f32` vector from the sensor frame to the body frame
+    pub fn apply_f32(&self, v: [f32; 3]) -> [f32; 3] {
+        match self {
+            Mounting::Axes(map) => map.apply_f32(v),
+            Mounting::Rotation(r) => r.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mul(a: [[i8; 3]; 3], b: [[i8; 3]; 3]) -> [[i8; 3]; 3] {
+        [0, 1, 2].map(|i| [0, 1, 2].map(|j| (0..3).map(|k| a[i][k] * b[k][j]).sum()))
+    }
+
+    fn det(m: [[i8; 3]; 3]) -> i8 {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    }
+
+    #[test]
+    fn test_orientations() {
+        let all = AxisMap::ORIENTATIONS;
+        for (i, a) in all.iter().enumerate() {
+            assert!(a.is_rotation());
+            assert_eq!(det(a.matrix()), 1);
+            assert!(all[..i].iter().all(|b| b != a));
+            assert_eq!(a.then(a.inverse()), AxisMap::IDENTITY);
+        }
+        for named in [
+            AxisMap::IDENTITY,
+            AxisMap::YAW_90,
+            AxisMap::YAW_180,
+            AxisMap::YAW_270,
+            AxisMap::ROLL_90,
+            AxisMap::ROLL_180,
+            AxisMap::ROLL_270,
+            AxisMap::PITCH_90,
+            AxisMap::PITCH_180,
+            AxisMap::PITCH_270,
+        ] {
+            assert!(all.contains(&named));
+        }
+
+        let mirror = AxisMap::new(Axis::PosY, Axis::PosX, Axis::PosZ);
+        assert!(!mirror.is_rotation());
+        assert_eq!(det(mirror.matrix()), -1);
+    }
+
+    #[test]
+    fn test_apply() {
+        let v = [100, -200, 300];
+        assert_eq!(AxisMap::YAW_90.apply(v), [200, 100, 300]);
+        assert_eq!(AxisMap::ROLL_180.apply(v), [100, 200, -300]);
+        assert_eq!(AxisMap::PITCH_90.apply(v), [300, -200, -100]);
+
+        // Composition matches the matrix product
+        let a = AxisMap::ROLL_90;
+        let b = AxisMap::YAW_270;
+        assert_eq!(a.then(b).matrix(), mul(b.matrix(), a.matrix()));
+        assert_eq!(a.then(b).apply(v), b.apply(a.apply(v)));
+
+        let m = AxisMap::YAW_90.matrix().map(|row| row.map(|x| x as f32));
+        assert_eq!(
+            Mounting::Rotation(m).apply(v),
+            Mounting::from(AxisMap::YAW_90).apply(v)
+        );
+    }
+}
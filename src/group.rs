@@ -0,0 +1,331 @@
+//! Synchronized sampling of several sensors
+//!
+//! A [`SensorGroup`] puts all its members in external trigger mode and fires their shared TRG
+//! line through an `OutputPin`, so every sensor starts its measurement at the same instant. The
+//! members usually sit on one SPI bus as `embedded-hal-bus` devices, each with its own chip
+//! select. After the measurement time every sensor's FIFO is read, giving one aligned array of
+//! samples per trigger with an error per sensor.
+
+use duplicate::duplicate_item;
+
+#[duplicate_item(
+    async_type           maybe_async_attr   SpiType;
+    [ non_blocking ]     [ must_be_async ]    [ embedded_hal_async ];
+    [ blocking ]         [ must_be_sync ]     [ embedded_hal ];
+)]
+pub mod async_type {
+    use crate::async_type::{Error, AK09940A};
+    use crate::ll::NoDelay;
+    use crate::sample::Sample;
+    use crate::states::{ExternalTrigger, NoPin, Powerdown};
+    use crate::stream::FIFO_DEPTH;
+    use crate::TRIGGER_PULSE_NS;
+    use embedded_hal::digital::OutputPin;
+    use maybe_async::maybe_async_attr;
+    use SpiType::delay::DelayNs;
+    use SpiType::spi::SpiDevice;
+
+    /// One sample or error per member, in member order
+    pub type GroupSamples<DEV, const N: usize> = [Result<Sample, Error<DEV>>; N];
+
+    /// Failed [`SensorGroup::new`]
+    ///
+    /// Gives back everything, the members that did enter external trigger mode are reset.
+    /// The drivers keep the settings they were given, FIFO watermark included.
+    pub struct SetupError<DEV, P, D, const N: usize, B = NoDelay>
+    where
+        DEV: SpiDevice,
+    {
+        pub drivers: [AK09940A<DEV, Powerdown, B>; N],
+        pub trg: P,
+        pub delay: D,
+        /// Why each member failed, `None` for those that did not; [`Error::Pin`] for all of
+        /// them if the TRG line could not be driven low. A member that entered external
+        /// trigger mode but could not be reset reports the reset error, it may still be in
+        /// that mode.
+        pub errors: [Option<Error<DEV>>; N],
+    }
+
+    impl<DEV, P, D, const N: usize, B> core::fmt::Debug for SetupError<DEV, P, D, N, B>
+    where
+        DEV: SpiDevice,
+    {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.debug_struct("SetupError")
+                .field("errors", &self.errors)
+                .finish_non_exhaustive()
+        }
+    }
+
+    /// Sensors sharing one TRG line
+    pub struct SensorGroup<DEV, P, D, const N: usize, B = NoDelay>
+    where
+        DEV: SpiDevice,
+    {
+        members: [AK09940A<DEV, ExternalTrigger, B>; N],
+        trg: P,
+        delay: D,
+        /// Frame number expected from each member at the next trigger
+        next_frame: [Option<u8>; N],
+    }
+
+    impl<DEV, P, D, const N: usize, B> SensorGroup<DEV, P, D, N, B>
+    where
+        DEV: SpiDevice,
+        P: OutputPin,
+        D: DelayNs,
+        B: DelayNs,
+    {
+        /// Put all `drivers` in external trigger mode, holding `trg` low meanwhile
+        ///
+        /// FIFO watermarks set on the drivers are ignored, every trigger yields one frame.
+        #[maybe_async_attr]
+        pub async fn new(
+            drivers: [AK09940A<DEV, Powerdown, B>; N],
+            mut trg: P,
+            delay: D,
+        ) -> Result<Self, SetupError<DEV, P, D, N, B>> {
+            // TRG must be low until DTSET is set
+            if trg.set_low().is_err() {
+                return Err(SetupError {
+                    drivers,
+                    trg,
+                    delay,
+                    errors: core::array::from_fn(|_| Some(Error::Pin)),
+                });
+            }
+
+            let mut drivers = drivers;
+            let mut errors: [Option<Error<DEV>>; N] = core::array::from_fn(|_| None);
+            for (driver, error) in drivers.iter_mut().zip(&mut errors) {
+                // One frame per trigger, whatever watermark the driver was set up with
+                *error = driver.enter_external_trigger(None).await.err();
+            }
+
+            if errors.iter().all(Option::is_none) {
+                return Ok(Self {
+                    members: drivers.map(|driver| {
+                        driver
+                            .with_fifo_watermark(None)
+                            .into_state(ExternalTrigger(NoPin))
+                    }),
+                    trg,
+                    delay,
+                    next_frame: [None; N],
+                });
+            }
+
+            // Back to power-down for the members that made it
+            for (driver, error) in drivers.iter_mut().zip(&mut errors) {
+                if error.is_none() {
+                    *error = driver.soft_reset().await.err();
+                }
+            }
+            Err(SetupError {
+                drivers,
+                trg,
+                delay,
+                errors,
+            })
+        }
+
+        /// Fire a TRG pulse, all members start a measurement
+        #[maybe_async_attr]
+        pub async fn trigger(&mut self) -> Result<(), Error<DEV>> {
+            self.trg.set_high().map_err(|_| Error::Pin)?;
+            self.delay.delay_ns(TRIGGER_PULSE_NS).await;
+            self.trg.set_low().map_err(|_| Error::Pin)?;
+            Ok(())
+        }
+
+        /// Trigger, wait for the measurement and read every member
+        ///
+        /// The wait is the measurement time of the slowest [`Drive`](crate::Drive) among the
+        /// members. An error on the TRG line fails the whole call, bus errors and members that
+        /// are not ready are reported per sensor.
+        #[maybe_async_attr]
+        pub async fn trigger_and_read(&mut self) -> Result<GroupSamples<DEV, N>, Error<DEV>> {
+            // The slowest drive among the members sets the wait
+            let mut measurement_time_us = 0;
+            for member in &mut self.members {
+                let drive = member.drive().await?;
+                measurement_time_us = measurement_time_us.max(drive.measurement_time_us());
+            }
+
+            self.trigger().await?;
+            self.delay.delay_us(measurement_time_us).await;
+            Ok(self.read().await)
+        }
+
+        /// Read the newest frame of every member
+        ///
+        /// Frames left over from earlier triggers, recognized by their `ST1` frame number,
+        /// are skipped so the samples stay aligned. A member without data reports
+        /// [`Error::SensorBusy`].
+        #[maybe_async_attr]
+        pub async fn read(&mut self) -> GroupSamples<DEV, N> {
+            let mut samples = core::array::from_fn(|_| Err(Error::SensorBusy));
+            for (i, member) in self.members.iter_mut().enumerate() {
+                let mut newest = Err(Error::SensorBusy);
+                // Without a previous frame to go by, drain the FIFO
+                let expected = self.next_frame[i];
+                for _ in 0..FIFO_DEPTH {
+                    let sample = match member.read_sample().await {
+                        Ok(sample) => sample,
+                        Err(e) => {
+                            newest = Err(e);
+                            break;
+                        }
+                    };
+                    if !sample.st1.data_ready() {
+                        break;
+                    }
+                    let frame = sample.st1.frame_number().value();
+                    self.next_frame[i] = Some((frame + 1) & 0x0F);
+                    newest = Ok(sample);
+                    if expected == Some(frame) {
+                        break;
+                    }
+                }
+                samples[i] = newest;
+            }
+            samples
+        }
+
+        /// The members, in the order given to [`SensorGroup::new`]
+        pub fn members(&mut self) -> &mut [AK09940A<DEV, ExternalTrigger, B>; N] {
+            &mut self.members
+        }
+
+        /// Give back the members, still in external trigger mode, the TRG pin and the delay
+        pub fn release(self) -> ([AK09940A<DEV, ExternalTrigger, B>; N], P, D) {
+            (self.members, self.trg, self.delay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::blocking::SensorGroup;
+    use crate::blocking::{Error, AK09940A};
+    use crate::test_util::{delay, frame, reset, transfer, Flaky};
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+    use embedded_hal_mock::eh1::digital::{
+        Mock as PinMock, State as PinState, Transaction as PinTransaction,
+    };
+    use embedded_hal_mock::eh1::spi::{Mock, Transaction};
+    use std::vec::Vec;
+
+    fn setup() -> Vec<Transaction<u8>> {
+        [
+            &reset(0x48)[..],
+            // DTSET, then external trigger mode with FIFO
+            &transfer([0xB0, 0x00], [0x00, 0x00]),
+            &transfer([0x30, 0x20], [0x00, 0x00]),
+            &transfer([0xB2, 0x00], [0x00, 0x00]),
+            &transfer([0x32, 0x98], [0x00, 0x00]),
+            &delay(300000),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn aligned_reads() {
+        let mut a = Mock::new(
+            &[
+                setup(),
+                // Two triggers before the first read, the FIFO is drained
+                frame(0x01, 10).to_vec(),
+                frame(0x03, 11).to_vec(),
+                frame(0x00, 0).to_vec(),
+                // Frame 2 is the expected one
+                frame(0x05, 12).to_vec(),
+            ]
+            .concat(),
+        );
+        let mut b = Mock::new(
+            &[
+                setup(),
+                frame(0x01, 20).to_vec(),
+                frame(0x03, 21).to_vec(),
+                frame(0x00, 0).to_vec(),
+                // Not ready after the third trigger
+                frame(0x00, 0).to_vec(),
+            ]
+            .concat(),
+        );
+        let pulse = [
+            PinTransaction::set(PinState::High),
+            PinTransaction::set(PinState::Low),
+        ];
+        let mut trg = PinMock::new(
+            &[
+                &[PinTransaction::set(PinState::Low)][..],
+                &pulse,
+                &pulse,
+                &pulse,
+            ]
+            .concat(),
+        );
+
+        let mut group = SensorGroup::new(
+            [AK09940A::new(a.clone()), AK09940A::new(b.clone())],
+            trg.clone(),
+            NoopDelay,
+        )
+        .unwrap();
+
+        group.trigger().unwrap();
+        group.trigger().unwrap();
+        let [sa, sb] = group.read();
+        assert_eq!(sa.unwrap().field[0], 11);
+        assert_eq!(sb.unwrap().field[0], 21);
+
+        let [sa, sb] = group.trigger_and_read().unwrap();
+        assert_eq!(sa.unwrap().field[0], 12);
+        assert!(matches!(sb, Err(Error::SensorBusy)));
+
+        group.release();
+        trg.done();
+        a.done();
+        b.done();
+    }
+
+    #[test]
+    fn failed_setup_gives_back_drivers() {
+        // Enters external trigger mode, then the reset fails
+        let setup_a = setup();
+        let mut a = Mock::new(&setup_a);
+        // Wrong company ID
+        let mut b = Mock::new(&reset(0x00));
+        let mut trg = PinMock::new(&[PinTransaction::set(PinState::Low)]);
+
+        let flaky = |spi: &Mock<u8>, pass| Flaky {
+            spi: spi.clone(),
+            pass,
+            failures: usize::MAX,
+        };
+        let err = SensorGroup::new(
+            [
+                AK09940A::new(flaky(&a, setup_a.len() / 3)).with_fifo_watermark(Some(3)),
+                AK09940A::new(flaky(&b, usize::MAX)).with_fifo_watermark(Some(3)),
+            ],
+            trg.clone(),
+            NoopDelay,
+        )
+        .err()
+        .expect("setup fails");
+
+        assert!(matches!(err.errors[0], Some(Error::Spi(_))));
+        assert!(matches!(err.errors[1], Some(Error::InvalidWhoAmI(0x00))));
+        for driver in &err.drivers {
+            assert_eq!(driver.fifo_watermark(), Some(3));
+        }
+
+        trg.done();
+        a.done();
+        b.done();
+    }
+}
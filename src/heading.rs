@@ -0,0 +1,374 @@
+//! Tilt-compensated compass heading
+//!
+//! Combines a calibrated magnetic field with the gravity vector from an accelerometer.
+//! Both must be in the same body frame: X forward, Y right, Z down. `gravity` points down,
+//! so a level device reads `(0, 0, +g)`; negate the output of an accelerometer that reports
+//! specific force. Any scale works for `gravity`, only its direction is used.
+//!
+//! The angles follow the aerospace convention: roll about X, then pitch about Y, heading
+//! clockwise from magnetic north seen from above.
+//!
+//! [`attitude`] works in `f32`. [`attitude_fixed`] takes the integer output of
+//! [`FixedCalibration`](crate::calibration::FixedCalibration) and an `i16` accelerometer
+//! reading and returns binary angles (65536 counts per turn), using only integer arithmetic.
+
+use core::f32::consts::{PI, TAU};
+
+/// Reason no reliable heading could be computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadingError {
+    /// The gravity vector is zero
+    NoGravity,
+    /// The field is weaker than the configured minimum, e.g. a shielded spot or a dead sensor
+    WeakField,
+    /// The field is too close to vertical, its horizontal part is dominated by noise
+    VerticalField,
+}
+
+/// Limits of the `f32` heading
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HeadingConfig {
+    /// Minimum field strength in LSB
+    pub min_field: f32,
+    /// Maximum magnitude of the field inclination in radians
+    pub max_inclination: f32,
+}
+
+impl Default for HeadingConfig {
+    /// 1000 LSB (10 µT) and 80°
+    fn default() -> Self {
+        Self {
+            min_field: 1000.0,
+            max_inclination: 80.0 * PI / 180.0,
+        }
+    }
+}
+
+/// Orientation relative to magnetic north, radians
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attitude {
+    /// Heading in `[0, 2π)`
+    pub heading: f32,
+    /// Pitch in `[-π/2, π/2]`, nose up is positive
+    pub pitch: f32,
+    /// Roll in `(-π, π]`, right side down is positive
+    pub roll: f32,
+    /// Inclination of the field, positive when it points below the horizon
+    pub inclination: f32,
+}
+
+/// Tilt-compensated heading from a calibrated field and a gravity vector
+pub fn attitude(
+    mag: [f32; 3],
+    gravity: [f32; 3],
+    config: &HeadingConfig,
+) -> Result<Attitude, HeadingError> {
+    let [gx, gy, gz] = gravity;
+    let g = libm::sqrtf(gx * gx + gy * gy + gz * gz);
+    if g == 0.0 || !g.is_finite() {
+        return Err(HeadingError::NoGravity);
+    }
+    let [bx, by, bz] = mag;
+    if libm::sqrtf(bx * bx + by * by + bz * bz) < config.min_field {
+        return Err(HeadingError::WeakField);
+    }
+
+    // At ±90° pitch roll is undefined, take it as zero
+    let r1 = libm::sqrtf(gy * gy + gz * gz);
+    let (roll, sin_roll, cos_roll) = if r1 > 0.0 {
+        (libm::atan2f(gy, gz), gy / r1, gz / r1)
+    } else {
+        (0.0, 0.0, 1.0)
+    };
+    let pitch = libm::atan2f(-gx, r1);
+    let (sin_pitch, cos_pitch) = (-gx / g, r1 / g);
+
+    // De-rotate into the horizontal plane
+    let bz_roll = by * sin_roll + bz * cos_roll;
+    let north = bx * cos_pitch + bz_roll * sin_pitch;
+    let east = bz * sin_roll - by * cos_roll;
+    let down = (bx * gx + by * gy + bz * gz) / g;
+
+    let inclination = libm::atan2f(down, libm::sqrtf(north * north + east * east));
+    if libm::fabsf(inclination) > config.max_inclination {
+        return Err(HeadingError::VerticalField);
+    }
+
+    let mut heading = libm::atan2f(east, north);
+    if heading < 0.0 {
+        heading += TAU;
+    }
+    Ok(Attitude {
+        heading,
+        pitch,
+        roll,
+        inclination,
+    })
+}
+
+/// Limits of the fixed-point heading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedHeadingConfig {
+    /// Minimum field strength in LSB
+    pub min_field: i32,
+    /// Maximum magnitude of the field inclination, 65536 counts per turn
+    pub max_inclination: u16,
+}
+
+impl Default for FixedHeadingConfig {
+    /// 1000 LSB (10 µT) and 80°
+    fn default() -> Self {
+        Self {
+            min_field: 1000,
+            max_inclination: (80 * 65536 / 360) as u16,
+        }
+    }
+}
+
+/// Orientation relative to magnetic north, binary angles with 65536 counts per turn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedAttitude {
+    /// Heading, 0 is north, 16384 is east
+    pub heading: u16,
+    /// Pitch, nose up is positive
+    pub pitch: i16,
+    /// Roll, right side down is positive
+    pub roll: i16,
+    /// Inclination of the field, positive when it points below the horizon
+    pub inclination: i16,
+}
+
+impl FixedAttitude {
+    /// Heading in degrees, `[0, 360)`
+    pub fn heading_degrees(&self) -> f32 {
+        self.heading as f32 * 360.0 / 65536.0
+    }
+}
+
+/// Fixed-point tilt-compensated heading
+///
+/// Same conventions as [`attitude`], `mag` in LSB and `gravity` in accelerometer counts.
+pub fn attitude_fixed(
+    mag: [i32; 3],
+    gravity: [i16; 3],
+    config: &FixedHeadingConfig,
+) -> Result<FixedAttitude, HeadingError> {
+    let [gx, gy, gz] = gravity.map(i128::from);
+    let g2 = gx * gx + gy * gy + gz * gz;
+    if g2 == 0 {
+        return Err(HeadingError::NoGravity);
+    }
+    let [bx, by, bz] = mag.map(i128::from);
+    let min = i128::from(config.min_field);
+    if bx * bx + by * by + bz * bz < min * min {
+        return Err(HeadingError::WeakField);
+    }
+
+    // At ±90° pitch roll is undefined, take it as zero
+    let (gy, gz) = if gy == 0 && gz == 0 { (0, 1) } else { (gy, gz) };
+    let (roll, _) = cordic(gy, gz);
+    let r1_sq = gy * gy + gz * gz;
+    let r1 = isqrt(r1_sq as u64) as i128;
+    let g = isqrt(g2 as u64) as i128;
+    let (pitch, _) = cordic(-gx, r1);
+
+    // The horizontal components and the vertical one, all scaled by r1 * g
+    let north = bx * r1_sq - (by * gy + bz * gz) * gx;
+    let east = (bz * gy - by * gz) * g;
+    let down = (bx * gx + by * gy + bz * gz) * r1;
+
+    let (heading, horizontal) = cordic(east, north);
+    let horizontal = (horizontal * CORDIC_INV_GAIN) >> 30;
+    let (inclination, _) = cordic(down, horizontal);
+
+    let inclination = to_binary_angle(inclination) as i16;
+    if inclination.unsigned_abs() > config.max_inclination {
+        return Err(HeadingError::VerticalField);
+    }
+    Ok(FixedAttitude {
+        heading: to_binary_angle(heading),
+        pitch: to_binary_angle(pitch) as i16,
+        roll: to_binary_angle(roll) as i16,
+        inclination,
+    })
+}
+
+/// Inputs are scaled down to this many bits so the CORDIC iterations cannot overflow
+const CORDIC_BITS: u32 = 30;
+
+/// `atan(2^-i)` with 2^32 counts per turn
+const CORDIC_ATAN: [u32; 31] = [
+    536870912, 316933406, 167458907, 85004756, 42667331, 21354465, 10679838, 5340245, 2670163,
+    1335087, 667544, 333772, 166886, 83443, 41722, 20861, 10430, 5215, 2608, 1304, 652, 326, 163,
+    81, 41, 20, 10, 5, 3, 1, 1,
+];
+
+/// Inverse of the CORDIC gain, Q2.30
+const CORDIC_INV_GAIN: i128 = 652032874;
+
+/// Angle of `(x, y)` with 2^32 counts per turn and the magnitude times the CORDIC gain
+fn cordic(y: i128, x: i128) -> (u32, i128) {
+    let shift =
+        (128 - y.unsigned_abs().max(x.unsigned_abs()).leading_zeros()).saturating_sub(CORDIC_BITS);
+    let (y, x) = ((y >> shift) as i64, (x >> shift) as i64);
+    // Vectoring only converges within ±90°, start from the right half plane
+    let (mut x, mut y, mut z) = if x < 0 {
+        (-x, -y, 0x8000_0000u32)
+    } else {
+        (x, y, 0)
+    };
+    for (i, &a) in CORDIC_ATAN.iter().enumerate() {
+        let (dx, dy) = (x >> i, y >> i);
+        if y > 0 {
+            x += dy;
+            y -= dx;
+            z = z.wrapping_add(a);
+        } else {
+            x -= dy;
+            y += dx;
+            z = z.wrapping_sub(a);
+        }
+    }
+    (z, (x as i128) << shift)
+}
+
+/// Round a 2^32-per-turn angle to 65536 counts per turn
+fn to_binary_angle(z: u32) -> u16 {
+    (z.wrapping_add(0x8000) >> 16) as u16
+}
+
+fn isqrt(n: u64) -> u64 {
+    if n < 2 {
+        return n;
+    }
+    let mut x = 1u64 << ((64 - n.leading_zeros()).div_ceil(2));
+    loop {
+        let y = (x + n / x) / 2;
+        if y >= x {
+            return x;
+        }
+        x = y;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// NED vector into the body frame after heading, pitch and roll
+    fn to_body(v: [f32; 3], heading: f32, pitch: f32, roll: f32) -> [f32; 3] {
+        let (s, c) = (libm::sinf(heading), libm::cosf(heading));
+        let v = [c * v[0] + s * v[1], -s * v[0] + c * v[1], v[2]];
+        let (s, c) = (libm::sinf(pitch), libm::cosf(pitch));
+        let v = [c * v[0] - s * v[2], v[1], s * v[0] + c * v[2]];
+        let (s, c) = (libm::sinf(roll), libm::cosf(roll));
+        [v[0], c * v[1] + s * v[2], -s * v[1] + c * v[2]]
+    }
+
+    fn angle_diff(a: f32, b: f32) -> f32 {
+        let d = libm::fmodf(a - b + 3.0 * PI, TAU) - PI;
+        libm::fabsf(d)
+    }
+
+    const DEG: f32 = PI / 180.0;
+
+    #[test]
+    fn test_attitude() {
+        // 50 µT at 60° inclination
+        let inclination = 60.0 * DEG;
+        let field = [
+            5000.0 * libm::cosf(inclination),
+            0.0,
+            5000.0 * libm::sinf(inclination),
+        ];
+        let config = HeadingConfig::default();
+        let fixed_config = FixedHeadingConfig::default();
+
+        for (heading, pitch, roll) in [
+            (0.0, 0.0, 0.0),
+            (45.0, 10.0, -20.0),
+            (200.0, -35.0, 60.0),
+            (300.0, 70.0, 150.0),
+            (90.0, -5.0, -170.0),
+        ] {
+            let (heading, pitch, roll) = (heading * DEG, pitch * DEG, roll * DEG);
+            let mag = to_body(field, heading, pitch, roll);
+            let gravity = to_body([0.0, 0.0, 9.81], heading, pitch, roll);
+
+            let a = attitude(mag, gravity, &config).unwrap();
+            assert!(angle_diff(a.heading, heading) < 1e-3, "{:?}", a);
+            assert!(angle_diff(a.pitch, pitch) < 1e-3);
+            assert!(angle_diff(a.roll, roll) < 1e-3);
+            assert!(angle_diff(a.inclination, inclination) < 1e-3);
+
+            let f = attitude_fixed(
+                mag.map(|v| libm::roundf(v) as i32),
+                gravity.map(|v| libm::roundf(v / 9.81 * 16384.0) as i16),
+                &fixed_config,
+            )
+            .unwrap();
+            let to_rad = |b: f32| b * TAU / 65536.0;
+            assert!(
+                angle_diff(to_rad(f.heading as f32), heading) < 0.5 * DEG,
+                "{:?}",
+                f
+            );
+            assert!(angle_diff(to_rad(f.pitch as f32), pitch) < 0.1 * DEG);
+            assert!(angle_diff(to_rad(f.roll as f32), roll) < 0.1 * DEG);
+            assert!(angle_diff(to_rad(f.inclination as f32), inclination) < 0.5 * DEG);
+        }
+    }
+
+    #[test]
+    fn test_unreliable() {
+        let config = HeadingConfig::default();
+        let fixed_config = FixedHeadingConfig::default();
+        let level = [0.0, 0.0, 1.0];
+
+        assert_eq!(
+            attitude([3000.0, 0.0, 0.0], [0.0; 3], &config),
+            Err(HeadingError::NoGravity)
+        );
+        assert_eq!(
+            attitude([300.0, 0.0, 400.0], level, &config),
+            Err(HeadingError::WeakField)
+        );
+        assert_eq!(
+            attitude([100.0, 100.0, 5000.0], level, &config),
+            Err(HeadingError::VerticalField)
+        );
+
+        assert_eq!(
+            attitude_fixed([3000, 0, 0], [0; 3], &fixed_config),
+            Err(HeadingError::NoGravity)
+        );
+        assert_eq!(
+            attitude_fixed([300, 0, 400], [0, 0, 16384], &fixed_config),
+            Err(HeadingError::WeakField)
+        );
+        assert_eq!(
+            attitude_fixed([100, 100, 5000], [0, 0, 16384], &fixed_config),
+            Err(HeadingError::VerticalField)
+        );
+    }
+
+    #[test]
+    fn test_isqrt() {
+        for n in [
+            0u64,
+            1,
+            2,
+            3,
+            4,
+            15,
+            16,
+            17,
+            1 << 40,
+            (1 << 32) - 1,
+            u32::MAX as u64 * 3,
+        ] {
+            let r = isqrt(n);
+            assert!(r * r <= n && (r + 1) * (r + 1) > n);
+        }
+    }
+}
@@ -1,9 +1,72 @@
 #![no_std]
 
+pub mod array;
+pub mod calibration;
+pub mod decimate;
+pub mod disturbance;
+pub mod dynamic;
+pub mod frame;
+pub mod group;
+pub mod heading;
 pub mod ll;
+mod math;
+pub mod notch;
+pub mod sample;
+pub mod split;
 pub mod states;
+pub mod stream;
+#[cfg(test)]
+mod test_util;
+pub mod time;
+#[cfg(feature = "wmm")]
+pub mod wmm;
 
 use duplicate::duplicate_item;
+use ll::reg::{CNTL1, CNTL3, HX, HY, HZ, ST1, ST2, TMPS};
+
+/// High time of a TRG pulse in ns, with margin over the datasheet minimum
+pub const TRIGGER_PULSE_NS: u32 = 10_000;
+
+/// Sensor drive, set by `CNTL1.MT2` and `CNTL3.MT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Drive {
+    LowPower1,
+    LowPower2,
+    LowNoise1,
+    LowNoise2,
+    UltraLowPower,
+}
+
+impl Drive {
+    /// Drive selected by the register values, `CNTL3.MT` is ignored when `CNTL1.MT2` is set
+    pub fn from_registers(cntl1: CNTL1, cntl3: CNTL3) -> Self {
+        if cntl1.mt2().value() == 1 {
+            return Drive::UltraLowPower;
+        }
+        match cntl3.measurement_type().value() {
+            0b00 => Drive::LowPower1,
+            0b01 => Drive::LowPower2,
+            0b10 => Drive::LowNoise1,
+            _ => Drive::LowNoise2,
+        }
+    }
+
+    /// Time from the falling edge of a TRG pulse until the measurement can be read, in µs
+    ///
+    /// Bounded by the period of the fastest continuous mode the drive supports.
+    pub const fn measurement_time_us(self) -> u32 {
+        match self {
+            Drive::LowPower1 => 400,
+            Drive::LowPower2 => 1_000,
+            Drive::LowNoise1 => 2_500,
+            Drive::LowNoise2 => 5_000,
+            Drive::UltraLowPower => 10_000,
+        }
+    }
+}
+
+/// One measurement as returned by `read_data`
+pub type Measurement = (ST1, HX, HY, HZ, TMPS, ST2);
 
 #[duplicate_item(
     async_type           maybe_async_attr   SpiType;
@@ -12,11 +75,13 @@ use duplicate::duplicate_item;
 )]
 pub mod async_type {
     use crate::ll::async_type::LL;
-    use crate::ll::reg::{self, RegAddress};
-    use crate::states::{Continuous, ExternalTrigger, Powerdown, SingleShot};
-    use arbitrary_int::{u1, u5};
+    use crate::ll::{self, reg, NoDelay};
+    use crate::states::{Continuous, ExternalTrigger, NoPin, Powerdown, SingleShot, TriggerPin};
+    use arbitrary_int::{u1, u3, u5};
+    use embedded_hal::digital::OutputPin;
     use embedded_hal::spi::Operation;
     use maybe_async::maybe_async_attr;
+    use SpiType::delay::DelayNs;
     use SpiType::spi::SpiDevice;
 
     pub enum Error<SPI>
@@ -31,14 +96,115 @@ pub mod async_type {
         InvalidMode,
         /// Invalid who am i
         InvalidWhoAmI(u8),
+        /// A control register did not read back the value written
+        WriteVerifyFailed {
+            reg: reg::RegAddress,
+            expected: u8,
+            actual: u8,
+        },
+        /// Error on the DRDY/TRG pin
+        Pin,
+        /// A cancelled mode switch of [`DynAK09940A`](crate::dynamic) dropped the driver
+        DriverLost,
+    }
+
+    impl<SPI> From<ll::Error<SPI::Error>> for Error<SPI>
+    where
+        SPI: SpiDevice,
+    {
+        fn from(e: ll::Error<SPI::Error>) -> Self {
+            match e {
+                ll::Error::Spi(e) => Error::Spi(e),
+                ll::Error::WriteVerifyFailed {
+                    reg,
+                    expected,
+                    actual,
+                } => Error::WriteVerifyFailed {
+                    reg,
+                    expected,
+                    actual,
+                },
+            }
+        }
+    }
+
+    impl<SPI> core::fmt::Debug for Error<SPI>
+    where
+        SPI: SpiDevice,
+    {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            match self {
+                Error::Spi(e) => f.debug_tuple("Spi").field(e).finish(),
+                Error::SensorBusy => f.write_str("SensorBusy"),
+                Error::InvalidMode => f.write_str("InvalidMode"),
+                Error::InvalidWhoAmI(w) => f.debug_tuple("InvalidWhoAmI").field(w).finish(),
+                Error::WriteVerifyFailed {
+                    reg,
+                    expected,
+                    actual,
+                } => f
+                    .debug_struct("WriteVerifyFailed")
+                    .field("reg", reg)
+                    .field("expected", expected)
+                    .field("actual", actual)
+                    .finish(),
+                Error::Pin => f.write_str("Pin"),
+                Error::DriverLost => f.write_str("DriverLost"),
+            }
+        }
+    }
+
+    /// A failed state transition
+    ///
+    /// Carries the driver back in the state it was in before the transition,
+    /// so the caller can retry or fall back without a second handle to the bus.
+    pub struct TransitionError<DEV, State, B = NoDelay>
+    where
+        DEV: SpiDevice,
+    {
+        pub driver: AK09940A<DEV, State, B>,
+        pub error: Error<DEV>,
+    }
+
+    impl<DEV, State, B> TransitionError<DEV, State, B>
+    where
+        DEV: SpiDevice,
+    {
+        /// Split into the driver and the error
+        pub fn into_parts(self) -> (AK09940A<DEV, State, B>, Error<DEV>) {
+            (self.driver, self.error)
+        }
+
+        /// Take back the driver, dropping the error
+        pub fn into_driver(self) -> AK09940A<DEV, State, B> {
+            self.driver
+        }
+    }
+
+    impl<DEV, State, B> core::fmt::Debug for TransitionError<DEV, State, B>
+    where
+        DEV: SpiDevice,
+    {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.debug_struct("TransitionError")
+                .field("error", &self.error)
+                .finish_non_exhaustive()
+        }
     }
 
+    /// AK09940A driver in type state `State`
+    ///
+    /// `B` is the delay spacing out retries after SPI errors, see
+    /// [`with_backoff_delay`](AK09940A::with_backoff_delay).
     #[derive(Debug, Clone)]
-    pub struct AK09940A<DEV, State>
+    pub struct AK09940A<DEV, State, B = NoDelay>
     where
         DEV: SpiDevice,
     {
-        pub dev: LL<DEV>,
+        pub dev: LL<DEV, B>,
+        temperature: bool,
+        mounting: Mounting,
+        fifo_watermark: Option<u8>,
         _state: State,
     }
 
@@ -48,83 +214,152 @@ pub mod async_type {
     {
         pub fn new(dev: DEV) -> Self {
             Self {
-                dev: LL { dev },
+                dev: LL::new(dev),
+                temperature: false,
+                mounting: Mounting::default(),
+                fifo_watermark: None,
                 _state: Powerdown,
             }
         }
     }
 
-    impl<DEV> AK09940A<DEV, Powerdown>
+    impl<DEV, B> AK09940A<DEV, Powerdown, B>
     where
         DEV: SpiDevice,
     {
-        /// Enter single-shot mode
-        #[maybe_async_attr]
-        pub async fn single_shot(mut self) -> Result<AK09940A<DEV, SingleShot>, Error<DEV>> {
-            // Reset the sensor
-            let cntl4 = reg::CNTL4::new_with_raw_value(0x00)
-                .with_soft_reset(true)
-                .raw_value();
-            self.dev
-                .write_reg(RegAddress::CNTL4, cntl4)
-                .await
-                .map_err(Error::Spi)?;
+        /// Enable or disable the temperature measurement (`CNTL2.TEM`)
+        ///
+        /// Applied whenever a measurement mode is entered, `TMPS` only holds valid data when enabled.
+        pub fn with_temperature(mut self, enable: bool) -> Self {
+            self.temperature = enable;
+            self
+        }
 
-            self.dev
-                .dev
-                .transaction(&mut [Operation::DelayNs(100000)])
-                .await
-                .map_err(Error::Spi)?;
+        /// Set how the chip is mounted, [`read_sample`](AK09940A::read_sample) then reports
+        /// the field in the body frame
+        pub fn with_mounting(mut self, mounting: impl Into<Mounting>) -> Self {
+            self.mounting = mounting.into();
+            self
+        }
 
-            let who_am_i = self
-                .dev
-                .read_reg(RegAddress::WIA1)
-                .await
-                .map_err(Error::Spi)?;
-            let who_am_i = crate::ll::reg::WIA1::new_with_raw_value(who_am_i);
-            if who_am_i.company_id() != 0x48 {
-                return Err(Error::InvalidWhoAmI(who_am_i.company_id()));
-            }
+        /// Use the FIFO in continuous mode, DRDY is raised once `watermark + 1` frames are
+        /// buffered (`watermark` from 0 to 7)
+        ///
+        /// External trigger mode always uses the FIFO, with a watermark of 0 unless set here.
+        pub fn with_fifo_watermark(mut self, watermark: Option<u8>) -> Self {
+            self.fifo_watermark = watermark.map(|wm| wm.min(7));
+            self
+        }
 
-            let cntl3 = self
-                .dev
-                .read_reg(RegAddress::CNTL3)
-                .await
-                .map_err(Error::Spi)?;
-            let cntl3 = crate::ll::reg::CNTL3::new_with_raw_value(cntl3)
-                .with_operation_mode(u5::new(0b00001))
-                .raw_value();
-            self.dev
-                .write_reg(RegAddress::CNTL3, cntl3)
-                .await
-                .map_err(Error::Spi)?;
+        /// Space out the retries of the [`RetryPolicy`](ll::RetryPolicy) on `delay` instead
+        /// of retrying right away
+        pub fn with_backoff_delay<D: DelayNs>(self, delay: D) -> AK09940A<DEV, Powerdown, D> {
+            AK09940A {
+                dev: self.dev.with_backoff_delay(delay),
+                temperature: self.temperature,
+                mounting: self.mounting,
+                fifo_watermark: self.fifo_watermark,
+                _state: Powerdown,
+            }
+        }
+    }
 
-            Ok(AK09940A {
-                dev: self.dev,
-                _state: SingleShot,
-            })
+    impl<DEV, B> AK09940A<DEV, Powerdown, B>
+    where
+        DEV: SpiDevice,
+        B: DelayNs,
+    {
+        /// Enter single-shot mode
+        ///
+        /// On failure the driver is given back in power-down mode together with the error.
+        #[maybe_async_attr]
+        pub async fn single_shot(
+            mut self,
+        ) -> Result<AK09940A<DEV, SingleShot, B>, TransitionError<DEV, Powerdown, B>> {
+            match self.enter_single_shot().await {
+                Ok(()) => Ok(self.into_state(SingleShot)),
+                Err(error) => Err(TransitionError {
+                    driver: self,
+                    error,
+                }),
+            }
         }
 
         /// Enter continuous mode N
         ///
         /// N from 1..=8 is the mode number
+        ///
+        /// On failure the driver is given back in power-down mode together with the error.
         #[maybe_async_attr]
         pub async fn continuous(
             mut self,
             mode: u8,
-        ) -> Result<AK09940A<DEV, Continuous>, Error<DEV>> {
-            if mode < 1 || mode > 8 {
-                return Err(Error::InvalidMode);
+        ) -> Result<AK09940A<DEV, Continuous, B>, TransitionError<DEV, Powerdown, B>> {
+            match self.enter_continuous(mode).await {
+                Ok(()) => Ok(self.into_state(Continuous)),
+                Err(error) => Err(TransitionError {
+                    driver: self,
+                    error,
+                }),
             }
+        }
 
-            // Reset the sensor
-            let cntl4 = reg::CNTL4::new_with_raw_value(0x00)
-                .with_soft_reset(true)
-                .raw_value();
-            self.dev
-                .write_reg(RegAddress::CNTL4, cntl4)
-                .await
-                .map_err(Error::Spi)?;
+        /// Enter external trigger mode
+        ///
+        /// Make sure that the DRDY/TRG pin is low until the DTSET bit is set, or let the driver
+        /// drive it with [`external_trigger_with_pin`](AK09940A::external_trigger_with_pin)
+        ///
+        /// On failure the driver is given back in power-down mode together with the error.
+        #[maybe_async_attr]
+        pub async fn external_trigger(
+            mut self,
+        ) -> Result<AK09940A<DEV, ExternalTrigger, B>, TransitionError<DEV, Powerdown, B>> {
+            match self.enter_external_trigger(self.fifo_watermark).await {
+                Ok(()) => Ok(self.into_state(ExternalTrigger(NoPin))),
+                Err(error) => Err(TransitionError {
+                    driver: self,
+                    error,
+                }),
+            }
+        }
+
+        /// Enter external trigger mode with the driver generating the pulses on `trg`, timed
+        /// by `delay`
+        ///
+        /// `trg` is held low while DTSET is set. On failure the pin and the delay are given
+        /// back along with the driver in power-down mode and the error.
+        #[maybe_async_attr]
+        #[allow(clippy::type_complexity)]
+        pub async fn external_trigger_with_pin<P: OutputPin, D: DelayNs>(
+            mut self,
+            mut trg: P,
+            delay: D,
+        ) -> Result<
+            AK09940A<DEV, ExternalTrigger<TriggerPin<P, D>>, B>,
+            (TransitionError<DEV, Powerdown, B>, P, D),
+        > {
+            let result = match trg.set_low() {
+                Ok(()) => self.enter_external_trigger(self.fifo_watermark).await,
+                Err(_) => Err(Error::Pin),
+            };
+            match result {
+                Ok(()) => Ok(self.into_state(ExternalTrigger(TriggerPin { pin: trg, delay }))),
+                Err(error) => Err((
+                    TransitionError {
+                        driver: self,
+                        error,
+                    },
+                    trg,
+                    delay,
+                )),
+            }
+        }
+
+        /// Soft reset the sensor and check the company ID
+        #[maybe_async_attr]
+        async fn reset_and_identify(&mut self) -> Result<(), Error<DEV>> {
+            let cntl4 = reg::CNTL4::new_with_raw_value(0x00).with_soft_reset(true);
+            self.dev.write(cntl4).await?;
 
             self.dev
                 .dev
@@ -132,93 +367,80 @@ pub mod async_type {
                 .await
                 .map_err(Error::Spi)?;
 
-            let who_am_i = self
-                .dev
-                .read_reg(RegAddress::WIA1)
-                .await
-                .map_err(Error::Spi)?;
-            let who_am_i = crate::ll::reg::WIA1::new_with_raw_value(who_am_i);
+            let who_am_i = self.dev.read::<reg::WIA1>().await.map_err(Error::Spi)?;
             if who_am_i.company_id() != 0x48 {
                 return Err(Error::InvalidWhoAmI(who_am_i.company_id()));
             }
 
-            let cntl3 = self
-                .dev
-                .read_reg(RegAddress::CNTL3)
-                .await
-                .map_err(Error::Spi)?;
-            let cntl3 = crate::ll::reg::CNTL3::new_with_raw_value(cntl3)
-                .with_operation_mode(u5::new(mode))
-                .raw_value();
-            self.dev
-                .write_reg(RegAddress::CNTL3, cntl3)
-                .await
-                .map_err(Error::Spi)?;
+            if self.temperature {
+                let cntl2 = reg::CNTL2::new_with_raw_value(0x00).with_temperature_enable(true);
+                self.dev.write(cntl2).await?;
+            }
 
-            Ok(AK09940A {
-                dev: self.dev,
-                _state: Continuous,
-            })
+            Ok(())
         }
 
-        /// Enter external trigger mode
-        ///
-        /// Make sure that the DRDY/TRG pin is low until the DTSET bit is set
         #[maybe_async_attr]
-        pub async fn external_trigger(
-            mut self,
-        ) -> Result<AK09940A<DEV, ExternalTrigger>, Error<DEV>> {
-            // Reset the sensor
-            let cntl4 = reg::CNTL4::new_with_raw_value(0x00)
-                .with_soft_reset(true)
-                .raw_value();
-            self.dev
-                .write_reg(RegAddress::CNTL4, cntl4)
-                .await
-                .map_err(Error::Spi)?;
+        async fn enter_single_shot(&mut self) -> Result<(), Error<DEV>> {
+            self.reset_and_identify().await?;
 
             self.dev
-                .dev
-                .transaction(&mut [Operation::DelayNs(100000)])
-                .await
-                .map_err(Error::Spi)?;
+                .modify(|cntl3: reg::CNTL3| cntl3.with_operation_mode(u5::new(0b00001)))
+                .await?;
 
-            let who_am_i = self
-                .dev
-                .read_reg(RegAddress::WIA1)
-                .await
-                .map_err(Error::Spi)?;
-            let who_am_i = crate::ll::reg::WIA1::new_with_raw_value(who_am_i);
-            if who_am_i.company_id() != 0x48 {
-                return Err(Error::InvalidWhoAmI(who_am_i.company_id()));
+            Ok(())
+        }
+
+        #[maybe_async_attr]
+        async fn enter_continuous(&mut self, mode: u8) -> Result<(), Error<DEV>> {
+            if !(1..=8).contains(&mode) {
+                return Err(Error::InvalidMode);
             }
 
-            let cntl1 = self
-                .dev
-                .read_reg(RegAddress::CNTL1)
-                .await
-                .map_err(Error::Spi)?;
-            let cntl1 = crate::ll::reg::CNTL1::new_with_raw_value(cntl1)
-                .with_drdy_trg_setting(u1::new(0b1))
-                .raw_value();
+            self.reset_and_identify().await?;
+
+            if let Some(wm) = self.fifo_watermark {
+                self.dev
+                    .modify(|cntl1: reg::CNTL1| cntl1.with_watermark_level(u3::new(wm)))
+                    .await?;
+            }
+
+            let fifo = self.fifo_watermark.is_some();
             self.dev
-                .write_reg(RegAddress::CNTL1, cntl1)
-                .await
-                .map_err(Error::Spi)?;
+                .modify(|cntl3: reg::CNTL3| {
+                    cntl3
+                        .with_operation_mode(u5::new(mode))
+                        .with_fifo_enable(fifo)
+                })
+                .await?;
 
-            let cntl3 = self
-                .dev
-                .read_reg(RegAddress::CNTL3)
-                .await
-                .map_err(Error::Spi)?;
-            let cntl3 = crate::ll::reg::CNTL3::new_with_raw_value(cntl3)
-                .with_operation_mode(u5::new(0b11000))
-                .with_fifo_enable(true)
-                .raw_value();
+            Ok(())
+        }
+
+        /// Configure external trigger mode with `watermark` instead of the driver's own
+        #[maybe_async_attr]
+        pub(crate) async fn enter_external_trigger(
+            &mut self,
+            watermark: Option<u8>,
+        ) -> Result<(), Error<DEV>> {
+            self.reset_and_identify().await?;
+
+            let wm = watermark.unwrap_or(0);
             self.dev
-                .write_reg(RegAddress::CNTL3, cntl3)
-                .await
-                .map_err(Error::Spi)?;
+                .modify(|cntl1: reg::CNTL1| {
+                    cntl1
+                        .with_drdy_trg_setting(u1::new(0b1))
+                        .with_watermark_level(u3::new(wm))
+                })
+                .await?;
+
+            self.dev
+                .modify(|cntl3: reg::CNTL3| {
+                    cntl3
+                        .with_operation_mode(u5::new(0b11000))
+                        .with_fifo_enable(true)
+                })
+                .await?;
 
             // Delay for 300us
             self.dev
@@ -227,38 +449,53 @@ pub mod async_type {
                 .await
                 .map_err(Error::Spi)?;
 
-            Ok(AK09940A {
-                dev: self.dev,
-                _state: ExternalTrigger,
-            })
+            Ok(())
         }
     }
 
-    impl<DEV, State> AK09940A<DEV, State>
+    impl<DEV, State, B> AK09940A<DEV, State, B>
     where
         DEV: SpiDevice,
+        B: DelayNs,
     {
+        pub(crate) fn into_state<Next>(self, state: Next) -> AK09940A<DEV, Next, B> {
+            AK09940A {
+                dev: self.dev,
+                temperature: self.temperature,
+                mounting: self.mounting,
+                fifo_watermark: self.fifo_watermark,
+                _state: state,
+            }
+        }
+
         /// Reset the AK09940A
         ///
-        /// Can be done from any state
+        /// Can be done from any state.
+        /// On failure the driver is given back in its current state together with the error.
         #[maybe_async_attr]
-        pub async fn reset(mut self) -> Result<AK09940A<DEV, Powerdown>, Error<DEV>> {
-            let cntl4 = reg::CNTL4::new_with_raw_value(0x00)
-                .with_soft_reset(true)
-                .raw_value();
-            self.dev
-                .write_reg(RegAddress::CNTL4, cntl4)
-                .await
-                .map_err(Error::Spi)?;
-            Ok(AK09940A {
-                dev: self.dev,
-                _state: Powerdown,
-            })
+        pub async fn reset(
+            mut self,
+        ) -> Result<AK09940A<DEV, Powerdown, B>, TransitionError<DEV, State, B>> {
+            match self.soft_reset().await {
+                Ok(()) => Ok(self.into_state(Powerdown)),
+                Err(error) => Err(TransitionError {
+                    driver: self,
+                    error,
+                }),
+            }
+        }
+
+        /// Soft reset without changing the type state
+        #[maybe_async_attr]
+        pub(crate) async fn soft_reset(&mut self) -> Result<(), Error<DEV>> {
+            let cntl4 = reg::CNTL4::new_with_raw_value(0x00).with_soft_reset(true);
+            self.dev.write(cntl4).await?;
+            Ok(())
         }
 
         /// Read the data from the AK09940A
         #[maybe_async_attr]
-        pub async fn read_data(&mut self) -> Result<(ST1, HX, HY, HZ, TMPS, ST2), Error<DEV>> {
+        pub async fn read_data(&mut self) -> Result<Measurement, Error<DEV>> {
             // Read in bulk
             let mut buf = [0x00; 12];
             self.dev
@@ -282,60 +519,365 @@ pub mod async_type {
                 ST2::new_with_raw_value(st2),
             ))
         }
+
+        /// Read the data from the AK09940A as a [`Sample`], in the body frame
+        ///
+        /// [`read_data`](AK09940A::read_data) returns the registers as is, in the sensor frame.
+        #[maybe_async_attr]
+        pub async fn read_sample(&mut self) -> Result<Sample, Error<DEV>> {
+            let mut sample = Sample::from(self.read_data().await?);
+            sample.field = self.mounting.apply(sample.field);
+            Ok(sample)
+        }
+
+        /// Read a sample, stamped with the time right before the read
+        #[maybe_async_attr]
+        pub async fn read_timestamped<C: Clock>(
+            &mut self,
+            clock: &mut C,
+        ) -> Result<Timestamped, Error<DEV>> {
+            let timestamp_us = clock.now_us();
+            let sample = self.read_sample().await?;
+            Ok(Timestamped {
+                sample,
+                timestamp_us,
+            })
+        }
+
+        /// Mounting applied by [`read_sample`](AK09940A::read_sample)
+        pub fn mounting(&self) -> &Mounting {
+            &self.mounting
+        }
+
+        /// FIFO watermark set with `with_fifo_watermark`, `None` if the FIFO is only used
+        /// in external trigger mode
+        pub fn fifo_watermark(&self) -> Option<u8> {
+            self.fifo_watermark
+        }
+
+        /// Sensor drive as configured, read from the shadow copy when possible
+        #[maybe_async_attr]
+        pub async fn drive(&mut self) -> Result<Drive, Error<DEV>> {
+            let cntl1 = self
+                .dev
+                .read_cached::<reg::CNTL1>()
+                .await
+                .map_err(Error::Spi)?;
+            let cntl3 = self
+                .dev
+                .read_cached::<reg::CNTL3>()
+                .await
+                .map_err(Error::Spi)?;
+            Ok(Drive::from_registers(cntl1, cntl3))
+        }
     }
 
     // -- Single-shot mode --
+    use crate::calibration::online::HardIronEstimator;
+    use crate::frame::Mounting;
     use crate::ll::reg::{HX, HY, HZ, ST1, ST2, TMPS};
+    use crate::sample::Sample;
+    use crate::time::{Clock, Timestamped};
+    use crate::{Drive, Measurement};
     use arbitrary_int::u24;
     use arrayref::array_ref;
 
-    impl<DEV> AK09940A<DEV, SingleShot>
+    impl<DEV, B> AK09940A<DEV, SingleShot, B>
     where
         DEV: SpiDevice,
+        B: DelayNs,
     {
         /// Start a new single-shot measurement
+        ///
+        /// The sensor returns to power-down by itself once the measurement is done, so `CNTL3`
+        /// is read from the sensor rather than the shadow copy. Returns [`Error::SensorBusy`]
+        /// if the previous measurement is still in progress.
         #[maybe_async_attr]
         pub async fn start_measurement(&mut self) -> Result<(), Error<DEV>> {
-            let cntl3 = self
-                .dev
-                .read_reg(RegAddress::CNTL3)
-                .await
-                .map_err(Error::Spi)?;
-            let cntl3 = crate::ll::reg::CNTL3::new_with_raw_value(cntl3);
-
+            let cntl3 = self.dev.read::<reg::CNTL3>().await.map_err(Error::Spi)?;
             if cntl3.operation_mode() != u5::new(0b00000) {
                 return Err(Error::SensorBusy);
             }
 
-            let cntl3 = cntl3.with_operation_mode(u5::new(0b00001)).raw_value();
             self.dev
-                .write_reg(RegAddress::CNTL3, cntl3)
-                .await
-                .map_err(Error::Spi)?;
+                .write(cntl3.with_operation_mode(u5::new(0b00001)))
+                .await?;
             Ok(())
         }
+
+        /// Check whether a single-shot measurement is still in progress
+        ///
+        /// Reads `CNTL3` from the sensor.
+        #[maybe_async_attr]
+        pub async fn is_busy(&mut self) -> Result<bool, Error<DEV>> {
+            let cntl3 = self.dev.read::<reg::CNTL3>().await.map_err(Error::Spi)?;
+            Ok(cntl3.operation_mode() != u5::new(0b00000))
+        }
     }
 
     // -- Continuous mode --
-    impl<DEV> AK09940A<DEV, Continuous> where DEV: SpiDevice {}
+    impl<DEV, B> AK09940A<DEV, Continuous, B>
+    where
+        DEV: SpiDevice,
+        B: DelayNs,
+    {
+        /// Read a sample and feed it to an online hard-iron estimator
+        #[maybe_async_attr]
+        pub async fn read_sample_and_update(
+            &mut self,
+            estimator: &mut HardIronEstimator,
+        ) -> Result<Sample, Error<DEV>> {
+            let sample = self.read_sample().await?;
+            estimator.update(&sample);
+            Ok(sample)
+        }
+    }
 
     // -- External trigger mode --
-    impl<DEV> AK09940A<DEV, ExternalTrigger>
+    impl<DEV, P, B> AK09940A<DEV, ExternalTrigger<P>, B>
     where
         DEV: SpiDevice,
+        B: DelayNs,
     {
         /// Start a new external trigger measurement
         #[maybe_async_attr]
         pub async fn start_waiting(&mut self) -> Result<(), Error<DEV>> {
-            let cntl3 = crate::ll::reg::CNTL3::new_with_raw_value(0x00)
-                .with_operation_mode(u5::new(0b11000))
-                .raw_value();
-            self.dev
-                .write_reg(RegAddress::CNTL3, cntl3)
-                .await
-                .map_err(Error::Spi)?;
+            let cntl3 = reg::CNTL3::new_with_raw_value(0x00).with_operation_mode(u5::new(0b11000));
+            self.dev.write(cntl3).await?;
+
+            Ok(())
+        }
+    }
 
+    impl<DEV, P, D, B> AK09940A<DEV, ExternalTrigger<TriggerPin<P, D>>, B>
+    where
+        DEV: SpiDevice,
+        B: DelayNs,
+        P: OutputPin,
+        D: DelayNs,
+    {
+        /// Generate a TRG pulse, the sensor starts a measurement on its falling edge
+        #[maybe_async_attr]
+        pub async fn trigger(&mut self) -> Result<(), Error<DEV>> {
+            let trg = &mut self._state.0;
+            trg.pin.set_high().map_err(|_| Error::Pin)?;
+            trg.delay.delay_ns(crate::TRIGGER_PULSE_NS).await;
+            trg.pin.set_low().map_err(|_| Error::Pin)?;
             Ok(())
         }
+
+        /// Trigger a measurement and read it once the measurement time of the configured
+        /// [`Drive`] has passed
+        ///
+        /// Returns [`Error::SensorBusy`] if the data is not ready by then. With a FIFO
+        /// watermark above 0 DRDY is only raised after several triggers, so this returns
+        /// [`Error::InvalidMode`] without triggering; use [`trigger`](AK09940A::trigger) and
+        /// read the FIFO instead.
+        #[maybe_async_attr]
+        pub async fn trigger_and_read(&mut self) -> Result<Sample, Error<DEV>> {
+            if self.fifo_watermark.is_some_and(|wm| wm > 0) {
+                return Err(Error::InvalidMode);
+            }
+            let measurement_time_us = self.drive().await?.measurement_time_us();
+
+            self.trigger().await?;
+            self._state.0.delay.delay_us(measurement_time_us).await;
+            let sample = self.read_sample().await?;
+            if !sample.st1.data_ready() {
+                return Err(Error::SensorBusy);
+            }
+            Ok(sample)
+        }
+
+        /// Take the TRG pin and the delay back, the driver stays in external trigger mode
+        pub fn release_pin(self) -> (AK09940A<DEV, ExternalTrigger, B>, P, D) {
+            let AK09940A {
+                dev,
+                temperature,
+                mounting,
+                fifo_watermark,
+                _state: ExternalTrigger(TriggerPin { pin, delay }),
+            } = self;
+            let driver = AK09940A {
+                dev,
+                temperature,
+                mounting,
+                fifo_watermark,
+                _state: ExternalTrigger(NoPin),
+            };
+            (driver, pin, delay)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::blocking::{Error, AK09940A};
+    use crate::frame::{AxisMap, Mounting};
+    use crate::ll::reg::CNTL3;
+    use crate::states::Powerdown;
+    use crate::test_util::{delay, frame, reset, transfer, Flaky};
+    use crate::{Drive, TRIGGER_PULSE_NS};
+    use arbitrary_int::u2;
+    use embedded_hal::spi::ErrorKind;
+    use embedded_hal_mock::eh1::delay::{CheckedDelay, NoopDelay, Transaction as DelayTransaction};
+    use embedded_hal_mock::eh1::digital::{
+        Mock as PinMock, State as PinState, Transaction as PinTransaction,
+    };
+    use embedded_hal_mock::eh1::spi::Mock;
+
+    #[test]
+    fn trigger_pin_is_driven() {
+        let expectations = [
+            &reset(0x48)[..],
+            &transfer([0xB0, 0x00], [0x00, 0x00]),
+            &transfer([0x30, 0x20], [0x00, 0x00]),
+            &transfer([0xB2, 0x00], [0x00, 0x00]),
+            &transfer([0x32, 0x98], [0x00, 0x00]),
+            &delay(300000),
+            // The pulse and the measurement time are waited out on the delay, off the bus
+            &frame(0x01, 0x2A),
+            // Low noise drive 2
+            &transfer([0x32, 0xF8], [0x00, 0x00]),
+            &frame(0x03, 0x2B),
+        ]
+        .concat();
+        let mut spi = Mock::new(&expectations);
+        let pulse = [
+            PinTransaction::set(PinState::High),
+            PinTransaction::set(PinState::Low),
+        ];
+        let mut trg =
+            PinMock::new(&[&[PinTransaction::set(PinState::Low)][..], &pulse, &pulse].concat());
+        let mut wait = CheckedDelay::new(&[
+            DelayTransaction::delay_ns(TRIGGER_PULSE_NS),
+            DelayTransaction::delay_us(400),
+            DelayTransaction::delay_ns(TRIGGER_PULSE_NS),
+            DelayTransaction::delay_us(5_000),
+        ]);
+
+        let mut mag = AK09940A::new(spi.clone())
+            .external_trigger_with_pin(trg.clone(), wait.clone())
+            .expect("external trigger mode");
+        assert_eq!(mag.drive().unwrap(), Drive::LowPower1);
+        assert_eq!(mag.trigger_and_read().unwrap().field[0], 0x2A);
+
+        mag.dev
+            .modify(|cntl3: CNTL3| cntl3.with_measurement_type(u2::new(0b11)))
+            .unwrap();
+        assert_eq!(mag.drive().unwrap(), Drive::LowNoise2);
+        assert_eq!(mag.trigger_and_read().unwrap().field[0], 0x2B);
+        mag.release_pin();
+
+        spi.done();
+        trg.done();
+        wait.done();
+    }
+
+    #[test]
+    fn trigger_and_read_rejects_watermark() {
+        let expectations = [
+            &reset(0x48)[..],
+            &transfer([0xB0, 0x00], [0x00, 0x00]),
+            &transfer([0x30, 0x21], [0x00, 0x00]),
+            &transfer([0xB2, 0x00], [0x00, 0x00]),
+            &transfer([0x32, 0x98], [0x00, 0x00]),
+            &delay(300000),
+        ]
+        .concat();
+        let mut spi = Mock::new(&expectations);
+        let mut trg = PinMock::new(&[PinTransaction::set(PinState::Low)]);
+
+        let mut mag = AK09940A::new(spi.clone())
+            .with_fifo_watermark(Some(1))
+            .external_trigger_with_pin(trg.clone(), NoopDelay)
+            .ok()
+            .expect("external trigger mode");
+        // DRDY would only rise after the second trigger, nothing is triggered or read
+        assert!(matches!(mag.trigger_and_read(), Err(Error::InvalidMode)));
+        mag.release_pin();
+
+        spi.done();
+        trg.done();
+    }
+
+    #[test]
+    fn single_shot_is_not_retriggered() {
+        let expectations = [
+            &reset(0x48)[..],
+            &transfer([0xB2, 0x00], [0x00, 0x00]),
+            &transfer([0x32, 0x01], [0x00, 0x00]),
+            // Still measuring
+            &transfer([0xB2, 0x00], [0x00, 0x01]),
+            // Back in power-down
+            &transfer([0xB2, 0x00], [0x00, 0x00]),
+            &transfer([0x32, 0x01], [0x00, 0x00]),
+        ]
+        .concat();
+        let mut spi = Mock::new(&expectations);
+
+        let mut mag = AK09940A::new(spi.clone()).single_shot().unwrap();
+        assert!(matches!(mag.start_measurement(), Err(Error::SensorBusy)));
+        mag.start_measurement().unwrap();
+
+        spi.done();
+    }
+
+    #[test]
+    fn failed_transition_gives_back_driver() {
+        let expectations = [
+            // Soft reset, identify
+            &reset(0x48)[..],
+            // CNTL3 is read once after the reset, then written
+            &transfer([0xB2, 0x00], [0x00, 0x00]),
+            &transfer([0x32, 0x03], [0x00, 0x00]),
+        ]
+        .concat();
+        let mut spi = Mock::new(&expectations);
+
+        let err = AK09940A::new(spi.clone()).continuous(9).unwrap_err();
+        assert!(matches!(err.error, Error::InvalidMode));
+
+        let mag = err.into_driver().continuous(3);
+        assert!(mag.is_ok());
+
+        spi.done();
+
+        let expectations = [
+            &reset(0x48)[..],
+            &transfer([0xB0, 0x00], [0x00, 0x00]),
+            // The CNTL1 write fails here, the retry starts over with a reset
+            &reset(0x48),
+            &transfer([0xB0, 0x00], [0x00, 0x00]),
+            &transfer([0x30, 0x02], [0x00, 0x00]),
+            &transfer([0xB2, 0x00], [0x00, 0x00]),
+            &transfer([0x32, 0x83], [0x00, 0x00]),
+        ]
+        .concat();
+        let mut spi = Mock::new(&expectations);
+        let flaky = Flaky {
+            spi: spi.clone(),
+            pass: 4,
+            failures: 1,
+        };
+
+        let err = AK09940A::new(flaky)
+            .with_mounting(AxisMap::YAW_90)
+            .with_fifo_watermark(Some(2))
+            .continuous(3)
+            .unwrap_err();
+        assert!(matches!(err.error, Error::Spi(ErrorKind::Other)));
+
+        // Back in power-down with its settings
+        let driver: AK09940A<_, Powerdown> = err.into_driver();
+        assert_eq!(driver.fifo_watermark(), Some(2));
+        assert_eq!(*driver.mounting(), Mounting::from(AxisMap::YAW_90));
+
+        let mag = driver.continuous(3);
+        assert!(mag.is_ok());
+
+        spi.done();
     }
 }
@@ -1,6 +1,108 @@
 pub mod reg;
 
 use duplicate::duplicate_item;
+use reg::RegAddress;
+
+/// Low level error
+#[derive(Debug)]
+pub enum Error<E> {
+    /// SPI communication error
+    Spi(E),
+    /// The value read back after a write does not match the value written
+    WriteVerifyFailed {
+        reg: RegAddress,
+        expected: u8,
+        actual: u8,
+    },
+}
+
+/// Whether writes to the control registers are read back and compared
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyPolicy {
+    /// Trust every write
+    #[default]
+    Disabled,
+    /// Read back every write, comparing only the readable bits (see [`RegAddress::readback_mask`]).
+    /// On mismatch the write is repeated up to `retries` times before failing.
+    ReadBack { retries: u8 },
+}
+
+/// How often idempotent bus accesses are repeated after an SPI error
+///
+/// Applies to register writes (writing the same value twice is harmless) and to reads, except
+/// reads of the measurement data (see [`RegAddress::reads_data`]). A failed data read may
+/// already have popped a frame, so its error is passed on for the caller to count the frame
+/// as lost.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Number of retries after the first failed attempt
+    pub retries: u8,
+    /// Delay before each retry, in nanoseconds
+    ///
+    /// Waited out on the delay given with `with_backoff_delay`, without one the retries follow
+    /// each other immediately.
+    pub backoff_ns: u32,
+}
+
+/// No backoff delay, retries are not spaced out
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoDelay;
+
+impl embedded_hal::delay::DelayNs for NoDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
+
+impl embedded_hal_async::delay::DelayNs for NoDelay {
+    async fn delay_ns(&mut self, _ns: u32) {}
+}
+
+/// Shadow copy of the writable control registers
+///
+/// Holds the last value written to (or read from) `CNTL1`, `CNTL2`, `CNTL3` and `I2CDIS`,
+/// so that read-modify-write sequences do not need a bus read.
+/// The copy is invalidated whenever `CNTL4` is written, since a soft reset restores the defaults.
+#[derive(Debug, Clone, Default)]
+pub struct Shadow {
+    cntl1: Option<u8>,
+    cntl2: Option<u8>,
+    cntl3: Option<u8>,
+    i2cdis: Option<u8>,
+}
+
+impl Shadow {
+    fn slot(&mut self, reg: RegAddress) -> Option<&mut Option<u8>> {
+        match reg {
+            RegAddress::CNTL1 => Some(&mut self.cntl1),
+            RegAddress::CNTL2 => Some(&mut self.cntl2),
+            RegAddress::CNTL3 => Some(&mut self.cntl3),
+            RegAddress::I2CDIS => Some(&mut self.i2cdis),
+            _ => None,
+        }
+    }
+
+    /// Get the cached value of a register, if any
+    pub fn get(&self, reg: RegAddress) -> Option<u8> {
+        match reg {
+            RegAddress::CNTL1 => self.cntl1,
+            RegAddress::CNTL2 => self.cntl2,
+            RegAddress::CNTL3 => self.cntl3,
+            RegAddress::I2CDIS => self.i2cdis,
+            _ => None,
+        }
+    }
+
+    /// Store the value of a register, ignored for registers that are not shadowed
+    pub fn set(&mut self, reg: RegAddress, value: u8) {
+        if let Some(slot) = self.slot(reg) {
+            *slot = Some(value);
+        }
+    }
+
+    /// Forget all cached values
+    pub fn invalidate(&mut self) {
+        *self = Self::default();
+    }
+}
 
 #[duplicate_item(
     async_type           maybe_async_attr   SpiType;
@@ -9,17 +111,25 @@ use duplicate::duplicate_item;
 )]
 pub mod async_type {
     use maybe_async::maybe_async_attr;
+    use SpiType::delay::DelayNs;
     use SpiType::spi::SpiDevice;
 
-    use super::reg::RegAddress;
+    use super::reg::{RawValue, Readable, RegAddress, Writable};
+    use super::{Error, NoDelay, RetryPolicy, Shadow, VerifyPolicy};
 
     /// Low level implementation of the AK09940A driver
+    ///
+    /// `B` is the delay spacing out retries, see [`RetryPolicy::backoff_ns`].
     #[derive(Debug, Clone)]
-    pub struct LL<DEV>
+    pub struct LL<DEV, B = NoDelay>
     where
         DEV: SpiDevice,
     {
         pub dev: DEV,
+        shadow: Shadow,
+        verify: VerifyPolicy,
+        retry: RetryPolicy,
+        backoff: B,
     }
 
     impl<DEV> LL<DEV>
@@ -27,35 +137,419 @@ pub mod async_type {
         DEV: SpiDevice,
     {
         pub fn new(dev: DEV) -> Self {
-            Self { dev }
+            Self {
+                dev,
+                shadow: Shadow::default(),
+                verify: VerifyPolicy::Disabled,
+                retry: RetryPolicy::default(),
+                backoff: NoDelay,
+            }
         }
     }
 
-    impl<DEV> LL<DEV>
+    impl<DEV, B> LL<DEV, B>
+    where
+        DEV: SpiDevice,
+    {
+        /// Wait out the retry backoff on `delay`, off the bus
+        pub fn with_backoff_delay<D: DelayNs>(self, delay: D) -> LL<DEV, D> {
+            LL {
+                dev: self.dev,
+                shadow: self.shadow,
+                verify: self.verify,
+                retry: self.retry,
+                backoff: delay,
+            }
+        }
+
+        /// Shadow copy of the control registers
+        pub fn shadow(&self) -> &Shadow {
+            &self.shadow
+        }
+
+        /// Forget the shadow copy, the next access to a control register will hit the bus
+        pub fn invalidate_shadow(&mut self) {
+            self.shadow.invalidate();
+        }
+
+        /// Set the read-back policy for register writes
+        pub fn set_verify_policy(&mut self, verify: VerifyPolicy) {
+            self.verify = verify;
+        }
+
+        /// Read-back policy for register writes
+        pub fn verify_policy(&self) -> VerifyPolicy {
+            self.verify
+        }
+
+        /// Set the retry policy for SPI errors
+        pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+            self.retry = retry;
+        }
+
+        /// Retry policy for SPI errors
+        pub fn retry_policy(&self) -> RetryPolicy {
+            self.retry
+        }
+    }
+
+    impl<DEV, B> LL<DEV, B>
     where
         DEV: SpiDevice,
+        B: DelayNs,
     {
+        /// Read a typed register
+        #[maybe_async_attr]
+        pub async fn read<R: Readable>(&mut self) -> Result<R, DEV::Error> {
+            let mut buf = [0x00; 4];
+            let buf = &mut buf[..=R::Raw::WIDTH];
+            buf[0] = R::ADDRESS as u8 | 0x80;
+            let retries = self.read_retries(R::ADDRESS, R::Raw::WIDTH);
+            self.transfer_in_place(buf, retries).await?;
+            if R::Raw::WIDTH == 1 {
+                self.shadow.set(R::ADDRESS, buf[1]);
+            }
+            Ok(R::from_raw(R::Raw::from_le_slice(&buf[1..])))
+        }
+
+        /// Read a typed register from the shadow copy, falling back to the bus if it is not cached
         #[maybe_async_attr]
-        pub async fn read_reg(&mut self, reg: RegAddress) -> Result<u8, DEV::Error> {
+        pub async fn read_cached<R: Readable>(&mut self) -> Result<R, DEV::Error> {
+            match self.shadow.get(R::ADDRESS) {
+                Some(value) if R::Raw::WIDTH == 1 => {
+                    Ok(R::from_raw(R::Raw::from_le_slice(&[value])))
+                }
+                _ => self.read::<R>().await,
+            }
+        }
+
+        /// Write a typed register
+        ///
+        /// Only registers implementing [`Writable`] are accepted.
+        /// Writing `CNTL4` invalidates the shadow copy.
+        #[maybe_async_attr]
+        pub async fn write<R: Writable>(&mut self, value: R) -> Result<(), Error<DEV::Error>> {
+            let mut buf = [0x00; 4];
+            let buf = &mut buf[..=R::Raw::WIDTH];
+            buf[0] = R::ADDRESS as u8;
+            value.to_raw().write_le_slice(&mut buf[1..]);
+
+            if R::Raw::WIDTH == 1 {
+                return self.write_reg(R::ADDRESS, buf[1]).await;
+            }
+            self.transfer_in_place(buf, self.retry.retries)
+                .await
+                .map_err(Error::Spi)
+        }
+
+        /// Read-modify-write a typed register
+        ///
+        /// The read is served from the shadow copy when possible.
+        #[maybe_async_attr]
+        pub async fn modify<R, F>(&mut self, f: F) -> Result<(), Error<DEV::Error>>
+        where
+            R: Readable + Writable,
+            F: FnOnce(R) -> R,
+        {
+            let value = self.read_cached::<R>().await.map_err(Error::Spi)?;
+            self.write(f(value)).await
+        }
+
+        /// Read a register by address
+        ///
+        /// Crate-internal, users go through [`LL::read`], which checks access rights at
+        /// compile time.
+        #[maybe_async_attr]
+        pub(crate) async fn read_reg(&mut self, reg: RegAddress) -> Result<u8, DEV::Error> {
             let mut buf = [reg as u8 | 0x80, 0x00];
-            self.dev.transfer_in_place(&mut buf).await?;
+            let retries = self.read_retries(reg, 1);
+            self.transfer_in_place(&mut buf, retries).await?;
+            self.shadow.set(reg, buf[1]);
             Ok(buf[1])
         }
 
+        /// Write a register by address
+        ///
+        /// Crate-internal, users go through [`LL::write`], which checks access rights at
+        /// compile time. The write is read back according to the [`VerifyPolicy`].
         #[maybe_async_attr]
-        pub async fn write_reg(&mut self, reg: RegAddress, value: u8) -> Result<(), DEV::Error> {
-            let mut buf = [reg as u8, value];
-            self.dev.transfer_in_place(&mut buf).await?;
-            Ok(())
+        pub(crate) async fn write_reg(
+            &mut self,
+            reg: RegAddress,
+            value: u8,
+        ) -> Result<(), Error<DEV::Error>> {
+            let mut attempt = 0;
+            loop {
+                let mut buf = [reg as u8, value];
+                self.transfer_in_place(&mut buf, self.retry.retries)
+                    .await
+                    .map_err(Error::Spi)?;
+
+                if reg == RegAddress::CNTL4 {
+                    self.shadow.invalidate();
+                    return Ok(());
+                }
+
+                let mask = reg.readback_mask();
+                let retries = match self.verify {
+                    VerifyPolicy::ReadBack { retries } if mask != 0 => retries,
+                    _ => {
+                        self.shadow.set(reg, value);
+                        return Ok(());
+                    }
+                };
+
+                // Caches what the sensor actually holds
+                let actual = self.read_reg(reg).await.map_err(Error::Spi)?;
+                if reg.readback_matches(value, actual) {
+                    self.shadow.set(reg, value);
+                    return Ok(());
+                }
+                if attempt >= retries {
+                    return Err(Error::WriteVerifyFailed {
+                        reg,
+                        expected: value,
+                        actual,
+                    });
+                }
+                attempt += 1;
+            }
         }
 
+        /// Read consecutive registers starting at `reg`
+        ///
+        /// Not repeated on errors if the block reaches the measurement data. Crate-internal,
+        /// the address is not checked.
         #[maybe_async_attr]
-        pub async fn read_block(
+        pub(crate) async fn read_block(
             &mut self,
             reg: RegAddress,
             buf: &mut [u8],
         ) -> Result<(), DEV::Error> {
-            self.dev.transfer(buf, &[reg as u8 | 0x80]).await
+            let retries = self.read_retries(reg, buf.len());
+            let mut attempt = 0;
+            loop {
+                match self.dev.transfer(buf, &[reg as u8 | 0x80]).await {
+                    Err(_) if attempt < retries => {
+                        attempt += 1;
+                        self.backoff().await;
+                    }
+                    result => return result,
+                }
+            }
+        }
+
+        /// Retries allowed for a read of `len` bytes from `reg`
+        fn read_retries(&self, reg: RegAddress, len: usize) -> u8 {
+            if reg.reads_data(len) {
+                0
+            } else {
+                self.retry.retries
+            }
+        }
+
+        /// Transfer `buf` in place, repeating it up to `retries` times
+        #[maybe_async_attr]
+        async fn transfer_in_place(
+            &mut self,
+            buf: &mut [u8],
+            retries: u8,
+        ) -> Result<(), DEV::Error> {
+            let mut request = [0x00; 4];
+            request[..buf.len()].copy_from_slice(buf);
+
+            let mut attempt = 0;
+            loop {
+                match self.dev.transfer_in_place(buf).await {
+                    Err(_) if attempt < retries => {
+                        attempt += 1;
+                        buf.copy_from_slice(&request[..buf.len()]);
+                        self.backoff().await;
+                    }
+                    result => return result,
+                }
+            }
+        }
+
+        #[maybe_async_attr]
+        async fn backoff(&mut self) {
+            if self.retry.backoff_ns > 0 {
+                self.backoff.delay_ns(self.retry.backoff_ns).await;
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::blocking::LL;
+    use super::reg::{RegAddress, CNTL1, CNTL3, CNTL4};
+    use super::{Error, RetryPolicy, VerifyPolicy};
+    use crate::test_util::{transfer, Flaky};
+    use arbitrary_int::u5;
+    use embedded_hal::spi::ErrorKind;
+    use embedded_hal_mock::eh1::delay::{CheckedDelay, Transaction as DelayTransaction};
+    use embedded_hal_mock::eh1::spi::{Mock, Transaction};
+    use std::vec;
+
+    #[test]
+    fn modify_uses_shadow() {
+        let expectations = [
+            // First modify reads CNTL3 from the bus
+            transfer([0xB2, 0x00], [0x00, 0x80]),
+            transfer([0x32, 0x82], [0x00, 0x00]),
+            // Second modify is served from the shadow copy
+            transfer([0x32, 0x81], [0x00, 0x00]),
+            // Soft reset invalidates it
+            transfer([0x33, 0x01], [0x00, 0x00]),
+            transfer([0xB2, 0x00], [0x00, 0x00]),
+            transfer([0x32, 0x01], [0x00, 0x00]),
+        ]
+        .concat();
+        let mut spi = Mock::new(&expectations);
+        let mut ll = LL::new(spi.clone());
+
+        ll.modify(|r: CNTL3| r.with_operation_mode(u5::new(2)))
+            .unwrap();
+        ll.modify(|r: CNTL3| r.with_operation_mode(u5::new(1)))
+            .unwrap();
+        ll.write(CNTL4::new_with_raw_value(0x00).with_soft_reset(true))
+            .unwrap();
+        ll.modify(|r: CNTL3| r.with_operation_mode(u5::new(1)))
+            .unwrap();
+
+        spi.done();
+    }
+
+    #[test]
+    fn verify_masks_and_retries() {
+        let expectations = [
+            // Undefined bits of CNTL1 are ignored
+            transfer([0x30, 0x20], [0x00, 0x00]),
+            transfer([0xB0, 0x00], [0x00, 0x38]),
+            // Mismatch, retried once, then reported
+            transfer([0x32, 0x98], [0x00, 0x00]),
+            transfer([0xB2, 0x00], [0x00, 0x00]),
+            transfer([0x32, 0x98], [0x00, 0x00]),
+            transfer([0xB2, 0x00], [0x00, 0x18]),
+            // MODE clears itself after a single-shot measurement
+            transfer([0x32, 0x01], [0x00, 0x00]),
+            transfer([0xB2, 0x00], [0x00, 0x00]),
+            // But not in continuous mode
+            transfer([0x32, 0x03], [0x00, 0x00]),
+            transfer([0xB2, 0x00], [0x00, 0x00]),
+            transfer([0x32, 0x03], [0x00, 0x00]),
+            transfer([0xB2, 0x00], [0x00, 0x00]),
+        ]
+        .concat();
+        let mut spi = Mock::new(&expectations);
+        let mut ll = LL::new(spi.clone());
+        ll.set_verify_policy(VerifyPolicy::ReadBack { retries: 1 });
+
+        ll.write(CNTL1::new_with_raw_value(0x20)).unwrap();
+        let err = ll
+            .write(
+                CNTL3::new_with_raw_value(0x00)
+                    .with_fifo_enable(true)
+                    .with_operation_mode(u5::new(0b11000)),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::WriteVerifyFailed {
+                reg: RegAddress::CNTL3,
+                expected: 0x98,
+                actual: 0x18,
+            }
+        ));
+        assert_eq!(ll.shadow().get(RegAddress::CNTL3), Some(0x18));
+
+        // Reads back as power-down, still a match
+        ll.write(CNTL3::new_with_raw_value(0x00).with_operation_mode(u5::new(0b00001)))
+            .unwrap();
+
+        // Only MODE differs, continuous mode was not entered
+        let err = ll
+            .write(CNTL3::new_with_raw_value(0x00).with_operation_mode(u5::new(0b00011)))
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            Error::WriteVerifyFailed {
+                reg: RegAddress::CNTL3,
+                expected: 0x03,
+                actual: 0x00,
+            }
+        ));
+
+        spi.done();
+    }
+
+    #[test]
+    fn retry_transient_errors() {
+        let expectations = transfer([0x80, 0x00], [0x00, 0x48]);
+        let mut spi = Mock::new(&expectations);
+        // Waited out off the bus, once per retry
+        let mut delay = CheckedDelay::new(&[
+            DelayTransaction::delay_ns(1000),
+            DelayTransaction::delay_ns(1000),
+        ]);
+        let mut ll = LL::new(Flaky {
+            spi: spi.clone(),
+            pass: 0,
+            failures: 1,
+        })
+        .with_backoff_delay(delay.clone());
+        ll.set_retry_policy(RetryPolicy {
+            retries: 1,
+            backoff_ns: 1000,
+        });
+
+        // First attempt fails, the retry succeeds
+        assert_eq!(ll.read_reg(RegAddress::WIA1).unwrap(), 0x48);
+
+        // Retries exhausted
+        ll.dev.failures = 2;
+        assert_eq!(ll.read_reg(RegAddress::WIA1), Err(ErrorKind::Other));
+
+        spi.done();
+        delay.done();
+    }
+
+    #[test]
+    fn data_reads_are_not_retried() {
+        let expectations = [
+            Transaction::transaction_start(),
+            Transaction::transfer(vec![0xA0], vec![0x01, 0x02, 0x03, 0x04, 0x05, 0x06]),
+            Transaction::transaction_end(),
+        ];
+        let mut spi = Mock::new(&expectations);
+        let mut ll = LL::new(Flaky {
+            spi: spi.clone(),
+            pass: 0,
+            failures: 1,
+        });
+        ll.set_retry_policy(RetryPolicy {
+            retries: 1,
+            backoff_ns: 0,
+        });
+
+        // The attempt may have popped a FIFO frame, the error is passed on
+        let mut frame = [0x00; 12];
+        assert_eq!(
+            ll.read_block(RegAddress::ST1, &mut frame),
+            Err(ErrorKind::Other)
+        );
+        ll.dev.failures = 1;
+        assert_eq!(ll.read_reg(RegAddress::ST2), Err(ErrorKind::Other));
+
+        // Other blocks are retried
+        ll.dev.failures = 1;
+        let mut sensitivity = [0x00; 6];
+        ll.read_block(RegAddress::SXL, &mut sensitivity).unwrap();
+        assert_eq!(sensitivity, [0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+
+        spi.done();
+    }
+}
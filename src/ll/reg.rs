@@ -15,6 +15,7 @@ pub fn sign_extend_u24(value: u24) -> i32 {
 }
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum RegAddress {
     WIA1 = 0x00,
     WIA2 = 0x01,
@@ -47,6 +48,213 @@ pub enum RegAddress {
     TS = 0x37,
 }
 
+impl RegAddress {
+    /// Bits of the register that read back what was written
+    ///
+    /// Undefined bits and self-clearing bits (`CNTL4.SRST`) are masked out. `CNTL3.MODE` only
+    /// clears itself after single-shot mode, see [`RegAddress::readback_matches`].
+    pub const fn readback_mask(self) -> u8 {
+        match self {
+            RegAddress::CNTL1 => 0b1010_0111,
+            RegAddress::CNTL2 => 0b0100_0000,
+            RegAddress::CNTL3 => 0b1111_1111,
+            RegAddress::I2CDIS => 0b1111_1111,
+            _ => 0b0000_0000,
+        }
+    }
+
+    /// Whether `actual`, read back after writing `written`, holds what was written
+    ///
+    /// Compares the bits of [`RegAddress::readback_mask`]. After writing single-shot mode to
+    /// `CNTL3` the sensor may already be back in power-down, so a `MODE` of 0 matches too.
+    pub const fn readback_matches(self, written: u8, actual: u8) -> bool {
+        const MODE: u8 = 0b0001_1111;
+        const SINGLE_SHOT: u8 = 0b0000_0001;
+
+        let mask = self.readback_mask();
+        if actual & mask == written & mask {
+            return true;
+        }
+        matches!(self, RegAddress::CNTL3)
+            && written & MODE == SINGLE_SHOT
+            && actual & mask == written & mask & !MODE
+    }
+
+    /// Whether reading `len` bytes starting at this register reaches the measurement data
+    ///
+    /// Reading `ST1` to `ST2` has side effects: it ends the data read and, with the FIFO
+    /// enabled, pops a frame.
+    pub const fn reads_data(self, len: usize) -> bool {
+        let start = self as usize;
+        start <= RegAddress::ST2 as usize && start + len > RegAddress::ST1 as usize
+    }
+}
+
+/// Raw storage type of a register, as transferred on the bus (little endian)
+pub trait RawValue: Copy {
+    /// Width of the register in bytes
+    const WIDTH: usize;
+
+    fn from_le_slice(bytes: &[u8]) -> Self;
+
+    fn write_le_slice(self, bytes: &mut [u8]);
+}
+
+impl RawValue for u8 {
+    const WIDTH: usize = 1;
+
+    fn from_le_slice(bytes: &[u8]) -> Self {
+        bytes[0]
+    }
+
+    fn write_le_slice(self, bytes: &mut [u8]) {
+        bytes[0] = self;
+    }
+}
+
+impl RawValue for u16 {
+    const WIDTH: usize = 2;
+
+    fn from_le_slice(bytes: &[u8]) -> Self {
+        u16::from_le_bytes([bytes[0], bytes[1]])
+    }
+
+    fn write_le_slice(self, bytes: &mut [u8]) {
+        bytes[..2].copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+impl RawValue for u24 {
+    const WIDTH: usize = 3;
+
+    fn from_le_slice(bytes: &[u8]) -> Self {
+        u24::from_le_bytes([bytes[0], bytes[1], bytes[2]])
+    }
+
+    fn write_le_slice(self, bytes: &mut [u8]) {
+        bytes[..3].copy_from_slice(&self.to_le_bytes());
+    }
+}
+
+/// A typed register of the AK09940A
+///
+/// Ties a bitfield type to its (first) address and its raw width.
+/// Access rights are expressed by the [`Readable`] and [`Writable`] marker traits.
+pub trait Register: Copy {
+    const ADDRESS: RegAddress;
+
+    type Raw: RawValue;
+
+    fn from_raw(raw: Self::Raw) -> Self;
+
+    fn to_raw(self) -> Self::Raw;
+}
+
+/// Marker for registers that may be read
+pub trait Readable: Register {}
+
+/// Marker for registers that may be written
+///
+/// Read-only registers and the factory test register do not implement this trait,
+/// so writing them is rejected at compile time:
+///
+/// ```compile_fail
+/// use ak09940a::ll::reg::WIA1;
+///
+/// fn write_who_am_i<D: embedded_hal::spi::SpiDevice>(ll: &mut ak09940a::ll::blocking::LL<D>) {
+///     let _ = ll.write(WIA1::new_with_raw_value(0x00));
+/// }
+/// ```
+///
+/// ```compile_fail
+/// use ak09940a::ll::reg::TS;
+///
+/// fn write_test<D: embedded_hal::spi::SpiDevice>(ll: &mut ak09940a::ll::blocking::LL<D>) {
+///     let _ = ll.write(TS::new_with_raw_value(0x00));
+/// }
+/// ```
+///
+/// Nor can they be accessed by address:
+///
+/// ```compile_fail
+/// use ak09940a::ll::reg::RegAddress;
+///
+/// fn write_who_am_i<D: embedded_hal::spi::SpiDevice>(ll: &mut ak09940a::ll::blocking::LL<D>) {
+///     let _ = ll.write_reg(RegAddress::WIA1, 0x00);
+/// }
+/// ```
+///
+/// ```compile_fail
+/// use ak09940a::ll::reg::RegAddress;
+///
+/// fn read_test<D: embedded_hal::spi::SpiDevice>(ll: &mut ak09940a::ll::blocking::LL<D>) {
+///     let _ = ll.read_reg(RegAddress::TS);
+/// }
+/// ```
+///
+/// ```
+/// use ak09940a::ll::reg::CNTL2;
+///
+/// fn enable_temperature<D: embedded_hal::spi::SpiDevice>(ll: &mut ak09940a::ll::blocking::LL<D>) {
+///     let _ = ll.write(CNTL2::new_with_raw_value(0x00).with_temperature_enable(true));
+/// }
+/// ```
+pub trait Writable: Register {}
+
+macro_rules! register {
+    (@impl $reg:ident, $raw:ty, $addr:ident) => {
+        impl Register for $reg {
+            const ADDRESS: RegAddress = RegAddress::$addr;
+
+            type Raw = $raw;
+
+            #[inline]
+            fn from_raw(raw: $raw) -> Self {
+                Self::new_with_raw_value(raw)
+            }
+
+            #[inline]
+            fn to_raw(self) -> $raw {
+                self.raw_value()
+            }
+        }
+    };
+    ($reg:ident, $raw:ty, $addr:ident, none) => {
+        register!(@impl $reg, $raw, $addr);
+    };
+    ($reg:ident, $raw:ty, $addr:ident, r) => {
+        register!(@impl $reg, $raw, $addr);
+        impl Readable for $reg {}
+    };
+    ($reg:ident, $raw:ty, $addr:ident, rw) => {
+        register!(@impl $reg, $raw, $addr);
+        impl Readable for $reg {}
+        impl Writable for $reg {}
+    };
+}
+
+register!(WIA1, u8, WIA1, r);
+register!(WIA2, u8, WIA2, r);
+register!(RSV1, u8, RSV1, r);
+register!(RSV2, u8, RSV2, r);
+register!(ST, u8, ST, r);
+register!(ST1, u8, ST1, r);
+register!(HX, u24, HXL, r);
+register!(HY, u24, HYL, r);
+register!(HZ, u24, HZL, r);
+register!(TMPS, u8, TMPS, r);
+register!(ST2, u8, ST2, r);
+register!(SX, u16, SXL, r);
+register!(SY, u16, SYL, r);
+register!(SZ, u16, SZL, r);
+register!(CNTL1, u8, CNTL1, rw);
+register!(CNTL2, u8, CNTL2, rw);
+register!(CNTL3, u8, CNTL3, rw);
+register!(CNTL4, u8, CNTL4, rw);
+register!(I2CDIS, u8, I2CDIS, rw);
+// The factory test register must not be accessed
+register!(TS, u8, TS, none);
+
 /// Register WIA1, address 0x00
 /// Company Identification Register
 /// Contains a fixed value (0x48) identifying the manufacturer.
@@ -98,6 +306,7 @@ pub struct ST {
 /// Status 1 Register
 /// Provides the measurement data frame number and data ready status.
 #[bitfield(u8)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct ST1 {
     #[bits(1..=4, r)]
     frame_number: u4,
@@ -156,6 +365,7 @@ impl HZ {
 /// Register TMPS, address 0x1A
 /// Temperature Sensor Data
 #[bitfield(u8)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct TMPS {
     #[bits(0..=7, r)]
     tmps: u8,
@@ -167,8 +377,7 @@ impl TMPS {
     /// Temp = 30C - TMPS / 1.7
     pub fn milli_celsius(&self) -> i32 {
         let tmps = self.raw_value() as i8 as i32;
-        let temp_10000 = 30000 - (tmps * 58823 / 100);
-        temp_10000 / 10
+        30000 - tmps * 588235 / 1000
     }
 }
 
@@ -176,6 +385,7 @@ impl TMPS {
 /// Status 2 Register
 /// Indicates overflow and data overrun status.
 #[bitfield(u8)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct ST2 {
     #[bit(1, r)]
     invalid_data: bool,
@@ -322,15 +532,47 @@ mod tests {
         assert_eq!(extended, 0x7FFFFF);
     }
 
+    #[test]
+    fn test_register_raw() {
+        let mut buf = [0u8; 3];
+        HX::new_with_raw_value(u24::new(0x123456))
+            .to_raw()
+            .write_le_slice(&mut buf);
+        assert_eq!(buf, [0x56, 0x34, 0x12]);
+        assert_eq!(HX::from_raw(u24::from_le_slice(&buf)).magnitude(), 0x123456);
+
+        let sx = SX::from_raw(u16::from_le_slice(&[0xCD, 0xAB]));
+        assert_eq!(sx.raw_value(), 0xABCD);
+
+        assert_eq!(<CNTL3 as Register>::ADDRESS, RegAddress::CNTL3);
+        assert_eq!(<HZ as Register>::ADDRESS, RegAddress::HZL);
+    }
+
+    #[test]
+    fn test_readback_matches() {
+        let cntl3 = RegAddress::CNTL3;
+        assert!(cntl3.readback_matches(0x01, 0x01));
+        assert!(cntl3.readback_matches(0x01, 0x00));
+        assert!(cntl3.readback_matches(0x41, 0x40));
+        assert!(!cntl3.readback_matches(0x41, 0x00));
+        assert!(!cntl3.readback_matches(0x98, 0x00));
+        assert!(!cntl3.readback_matches(0x03, 0x01));
+        assert!(RegAddress::CNTL1.readback_matches(0x20, 0x38));
+    }
+
     #[test]
     fn test_tmps() {
         let tmps = TMPS::new_with_raw_value(0x00);
-        assert_eq!(tmps.milli_celsius(), 3000);
+        assert_eq!(tmps.milli_celsius(), 30000);
+
+        // -20 C reads (30 - -20) * 1.7 = 85
+        let tmps = TMPS::new_with_raw_value(85);
+        assert_eq!(tmps.milli_celsius(), -19999);
 
         let tmps = TMPS::new_with_raw_value(0x7F);
-        assert_eq!(tmps.milli_celsius(), -4470);
+        assert_eq!(tmps.milli_celsius(), -44705);
 
         let tmps = TMPS::new_with_raw_value(0x80);
-        assert_eq!(tmps.milli_celsius(), 10529);
+        assert_eq!(tmps.milli_celsius(), 105294);
     }
 }
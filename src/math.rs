@@ -0,0 +1,155 @@
+//! Small dense linear algebra for the calibration and processing modules
+
+pub(crate) type Mat3 = [[f64; 3]; 3];
+
+pub(crate) const IDENTITY3: Mat3 = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+/// Solve `a x = b` by Gaussian elimination with partial pivoting
+///
+/// Returns `None` if `a` is (numerically) singular.
+pub(crate) fn solve<const N: usize>(mut a: [[f64; N]; N], mut b: [f64; N]) -> Option<[f64; N]> {
+    let scale = a
+        .iter()
+        .flatten()
+        .fold(0.0f64, |m, v| if v.abs() > m { v.abs() } else { m });
+    if scale == 0.0 {
+        return None;
+    }
+
+    for col in 0..N {
+        let pivot = (col..N)
+            .max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))
+            .unwrap();
+        if a[pivot][col].abs() <= scale * 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        let (top, bottom) = a.split_at_mut(col + 1);
+        let pivot_row = &top[col];
+        for (row, rhs) in bottom.iter_mut().zip(col + 1..N) {
+            let f = row[col] / pivot_row[col];
+            for (x, p) in row[col..].iter_mut().zip(&pivot_row[col..]) {
+                *x -= f * p;
+            }
+            b[rhs] -= f * b[col];
+        }
+    }
+
+    let mut x = [0.0; N];
+    for row in (0..N).rev() {
+        let mut acc = b[row];
+        for k in row + 1..N {
+            acc -= a[row][k] * x[k];
+        }
+        x[row] = acc / a[row][row];
+    }
+    Some(x)
+}
+
+/// Eigen decomposition of a symmetric 3x3 matrix (cyclic Jacobi)
+///
+/// Returns the eigenvalues in ascending order and the matching eigenvectors as columns.
+pub(crate) fn sym_eigen3(mut a: Mat3) -> ([f64; 3], Mat3) {
+    let mut v = IDENTITY3;
+    for _ in 0..50 {
+        let off = a[0][1] * a[0][1] + a[0][2] * a[0][2] + a[1][2] * a[1][2];
+        if off < 1e-30 {
+            break;
+        }
+        for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() < 1e-300 {
+                continue;
+            }
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + libm::sqrt(theta * theta + 1.0));
+            let c = 1.0 / libm::sqrt(t * t + 1.0);
+            let s = t * c;
+            for row in a.iter_mut() {
+                let akp = row[p];
+                let akq = row[q];
+                row[p] = c * akp - s * akq;
+                row[q] = s * akp + c * akq;
+            }
+            let (ap, aq) = (a[p], a[q]);
+            for k in 0..3 {
+                a[p][k] = c * ap[k] - s * aq[k];
+                a[q][k] = s * ap[k] + c * aq[k];
+            }
+            for row in v.iter_mut() {
+                let vp = row[p];
+                let vq = row[q];
+                row[p] = c * vp - s * vq;
+                row[q] = s * vp + c * vq;
+            }
+        }
+    }
+
+    let mut order = [0, 1, 2];
+    order.sort_unstable_by(|&i, &j| a[i][i].total_cmp(&a[j][j]));
+    let values = order.map(|i| a[i][i]);
+    let vectors = [0, 1, 2].map(|r| order.map(|i| v[r][i]));
+    (values, vectors)
+}
+
+pub(crate) fn mat_mul(a: &Mat3, b: &Mat3) -> Mat3 {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+pub(crate) fn mat_vec(a: &Mat3, v: &[f64; 3]) -> [f64; 3] {
+    a.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+pub(crate) fn transpose(a: &Mat3) -> Mat3 {
+    [0, 1, 2].map(|i| [a[0][i], a[1][i], a[2][i]])
+}
+
+pub(crate) fn norm(v: &[f64; 3]) -> f64 {
+    libm::sqrt(v[0] * v[0] + v[1] * v[1] + v[2] * v[2])
+}
+
+/// `V diag(d) V^T` for the eigenvectors `V` (as columns)
+pub(crate) fn from_eigen(d: [f64; 3], v: &Mat3) -> Mat3 {
+    let mut vd = *v;
+    for row in vd.iter_mut() {
+        for (x, di) in row.iter_mut().zip(d) {
+            *x *= di;
+        }
+    }
+    mat_mul(&vd, &transpose(v))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solve() {
+        let a = [[2.0, 1.0, -1.0], [-3.0, -1.0, 2.0], [-2.0, 1.0, 2.0]];
+        let x = solve(a, [8.0, -11.0, -3.0]).unwrap();
+        for (x, e) in x.iter().zip([2.0, 3.0, -1.0]) {
+            assert!((x - e).abs() < 1e-12);
+        }
+        assert!(solve([[1.0, 2.0], [2.0, 4.0]], [1.0, 2.0]).is_none());
+    }
+
+    #[test]
+    fn test_sym_eigen3() {
+        let a = [[4.0, 1.0, 0.5], [1.0, 3.0, 0.2], [0.5, 0.2, 1.0]];
+        let (d, v) = sym_eigen3(a);
+        assert!(d[0] <= d[1] && d[1] <= d[2]);
+        let back = from_eigen(d, &v);
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!((back[i][j] - a[i][j]).abs() < 1e-9);
+            }
+        }
+    }
+}
@@ -0,0 +1,430 @@
+//! Mains hum filtering
+//!
+//! Indoor installations see strong 50 or 60 Hz fields and their harmonics. The sensor samples
+//! them without an anti-aliasing filter, so at low output data rates they show up at their
+//! aliases instead. A [`MainsFilter`] places an IIR notch on each harmonic's aliased frequency
+//! for the configured output data rate and filters the three axes independently. Harmonics that
+//! alias to (almost) DC are skipped, a notch there would remove the Earth's field.
+//!
+//! [`MainsDetector`] decides from the data whether the hum is at 50 or 60 Hz.
+
+use crate::sample::Sample;
+use core::f32::consts::PI;
+use heapless::Vec;
+
+/// Most notches a [`MainsFilter`] holds
+pub const MAX_NOTCHES: usize = 8;
+
+/// Aliases closer than this to DC, or to another notch, are skipped, in Hz
+const MIN_ALIAS_HZ: f32 = 0.5;
+
+/// Invalid filter or detector settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The output data rate must be finite and positive
+    OutputDataRate,
+    /// The quality factor must be finite and positive
+    Q,
+    /// The continuous mode must be from 1 to 8
+    Mode,
+}
+
+/// Mains frequency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mains {
+    Hz50,
+    Hz60,
+}
+
+impl Mains {
+    pub fn hz(self) -> f32 {
+        match self {
+            Mains::Hz50 => 50.0,
+            Mains::Hz60 => 60.0,
+        }
+    }
+}
+
+/// Frequency at which a tone at `hz` appears when sampled at `odr_hz`
+pub fn alias(hz: f32, odr_hz: f32) -> f32 {
+    libm::fabsf(hz - odr_hz * libm::roundf(hz / odr_hz))
+}
+
+/// Design of a [`MainsFilter`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NotchConfig {
+    /// Number of harmonics including the fundamental, at most [`MAX_NOTCHES`]
+    pub harmonics: u8,
+    /// Quality factor, the notch is `frequency / q` wide
+    pub q: f32,
+}
+
+impl Default for NotchConfig {
+    /// Fundamental and the next two harmonics, Q of 10
+    fn default() -> Self {
+        Self {
+            harmonics: 3,
+            q: 10.0,
+        }
+    }
+}
+
+/// Notch biquad, normalized to `a0 = 1`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Biquad {
+    hz: f32,
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Biquad {
+    /// Zeros on the unit circle at `hz`, poles just inside, unity gain at DC
+    ///
+    /// Unlike the usual `sin(w0) / 2Q` design this stays stable for a notch at Nyquist.
+    fn notch(hz: f32, odr_hz: f32, q: f32) -> Self {
+        let w0 = 2.0 * PI * hz / odr_hz;
+        let cos = libm::cosf(w0);
+        let r = (1.0 - PI * hz / q / odr_hz).clamp(0.0, 0.9999);
+        let (a1, a2) = (-2.0 * r * cos, r * r);
+        let g = (1.0 + a1 + a2) / (2.0 - 2.0 * cos);
+        Self {
+            hz,
+            b0: g,
+            b1: -2.0 * cos * g,
+            b2: g,
+            a1,
+            a2,
+        }
+    }
+
+    /// Transposed direct form II
+    fn run(&self, state: &mut [f32; 2], x: f32) -> f32 {
+        let y = self.b0 * x + state[0];
+        state[0] = self.b1 * x - self.a1 * y + state[1];
+        state[1] = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// Notches on the mains harmonics, per axis, see the [module documentation](self)
+#[derive(Debug, Clone)]
+pub struct MainsFilter {
+    notches: Vec<Biquad, MAX_NOTCHES>,
+    state: [[[f32; 2]; 3]; MAX_NOTCHES],
+    /// The first output is the input, so the filters start from it instead of from zero
+    primed: bool,
+}
+
+impl MainsFilter {
+    /// Notches for `mains` at an output data rate of `odr_hz`
+    ///
+    /// Pass the measured rate, e.g. from [`crate::time::OdrEstimator::odr_hz`], for notches
+    /// narrower than the oscillator tolerance.
+    pub fn new(mains: Mains, odr_hz: f32, config: &NotchConfig) -> Result<Self, ConfigError> {
+        if !(odr_hz.is_finite() && odr_hz > 0.0) {
+            return Err(ConfigError::OutputDataRate);
+        }
+        if !(config.q.is_finite() && config.q > 0.0) {
+            return Err(ConfigError::Q);
+        }
+
+        let mut notches: Vec<Biquad, MAX_NOTCHES> = Vec::new();
+        for k in 1..=config.harmonics.min(MAX_NOTCHES as u8) {
+            let hz = alias(mains.hz() * k as f32, odr_hz);
+            let taken = notches
+                .iter()
+                .any(|n| libm::fabsf(n.hz - hz) < MIN_ALIAS_HZ);
+            if hz < MIN_ALIAS_HZ || taken {
+                continue;
+            }
+            let _ = notches.push(Biquad::notch(hz, odr_hz, config.q));
+        }
+        Ok(Self {
+            notches,
+            state: [[[0.0; 2]; 3]; MAX_NOTCHES],
+            primed: false,
+        })
+    }
+
+    /// Notches for continuous mode `mode` (1..=8) at its nominal output data rate
+    pub fn for_mode(mains: Mains, mode: u8, config: &NotchConfig) -> Result<Self, ConfigError> {
+        let odr = crate::time::nominal_odr_hz(mode).ok_or(ConfigError::Mode)?;
+        Self::new(mains, odr as f32, config)
+    }
+
+    /// Notch frequencies in Hz, after aliasing
+    pub fn frequencies(&self) -> impl Iterator<Item = f32> + '_ {
+        self.notches.iter().map(|n| n.hz)
+    }
+
+    /// Forget the filter history
+    pub fn reset(&mut self) {
+        self.state = [[[0.0; 2]; 3]; MAX_NOTCHES];
+        self.primed = false;
+    }
+
+    /// Filter one field vector
+    pub fn process(&mut self, field: [f32; 3]) -> [f32; 3] {
+        if !self.primed {
+            // Steady state for a constant input: the notches pass DC with unity gain
+            for (notch, state) in self.notches.iter().zip(&mut self.state) {
+                for (s, x) in state.iter_mut().zip(field) {
+                    *s = [x * (1.0 - notch.b0), x * (notch.b2 - notch.a2)];
+                }
+            }
+            self.primed = true;
+        }
+        let mut out = field;
+        for (notch, state) in self.notches.iter().zip(&mut self.state) {
+            for (s, x) in state.iter_mut().zip(&mut out) {
+                *x = notch.run(s, *x);
+            }
+        }
+        out
+    }
+
+    /// Filter the field of a sample, in LSB
+    pub fn process_sample(&mut self, sample: &Sample) -> [f32; 3] {
+        self.process(sample.field.map(|v| v as f32))
+    }
+}
+
+/// Result of one [`MainsDetector`] window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Detection {
+    /// `None` if neither frequency dominates, or both alias to the same frequency
+    pub mains: Option<Mains>,
+    /// Hum amplitude at 50 Hz in LSB, summed over the axes
+    pub amplitude_50: f32,
+    /// Hum amplitude at 60 Hz in LSB, summed over the axes
+    pub amplitude_60: f32,
+}
+
+/// Goertzel state at one frequency
+#[derive(Debug, Clone, Copy)]
+struct Goertzel {
+    coeff: f32,
+    /// Gain of the first difference at this frequency
+    gain: f32,
+    state: [[f32; 2]; 3],
+}
+
+impl Goertzel {
+    fn new(hz: f32, odr_hz: f32) -> Self {
+        let w = 2.0 * PI * hz / odr_hz;
+        Self {
+            coeff: 2.0 * libm::cosf(w),
+            gain: 2.0 * libm::sinf(w / 2.0),
+            state: [[0.0; 2]; 3],
+        }
+    }
+
+    fn update(&mut self, x: [f32; 3]) {
+        for (s, x) in self.state.iter_mut().zip(x) {
+            let s0 = x + self.coeff * s[0] - s[1];
+            *s = [s0, s[0]];
+        }
+    }
+
+    /// Amplitude summed over the axes, then restart
+    fn take(&mut self, n: u32) -> f32 {
+        let mut amplitude = 0.0;
+        for [s1, s2] in self.state {
+            let power = s1 * s1 + s2 * s2 - self.coeff * s1 * s2;
+            amplitude += 2.0 * libm::sqrtf(power.max(0.0)) / n as f32;
+        }
+        self.state = [[0.0; 2]; 3];
+        if self.gain > 0.0 {
+            amplitude / self.gain
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Tells 50 from 60 Hz hum
+///
+/// Measures the amplitude at both (aliased) frequencies over windows of `window` samples,
+/// on the first difference of the field so the Earth's field does not leak in. The window
+/// should span at least a few cycles of the difference between the two aliases.
+#[derive(Debug, Clone)]
+pub struct MainsDetector {
+    hz50: Goertzel,
+    hz60: Goertzel,
+    distinct: bool,
+    window: u32,
+    count: u32,
+    last: Option<[f32; 3]>,
+}
+
+impl MainsDetector {
+    /// Amplitude ratio one frequency needs over the other to be reported
+    pub const DOMINANCE: f32 = 3.0;
+
+    /// Detector at an output data rate of `odr_hz`, reporting every `window` samples
+    pub fn new(odr_hz: f32, window: u32) -> Result<Self, ConfigError> {
+        if !(odr_hz.is_finite() && odr_hz > 0.0) {
+            return Err(ConfigError::OutputDataRate);
+        }
+
+        let (a50, a60) = (alias(50.0, odr_hz), alias(60.0, odr_hz));
+        Ok(Self {
+            hz50: Goertzel::new(a50, odr_hz),
+            hz60: Goertzel::new(a60, odr_hz),
+            distinct: libm::fabsf(a50 - a60) >= MIN_ALIAS_HZ
+                && a50 >= MIN_ALIAS_HZ
+                && a60 >= MIN_ALIAS_HZ,
+            window: window.max(2),
+            count: 0,
+            last: None,
+        })
+    }
+
+    /// Whether the output data rate lets 50 and 60 Hz be told apart at all
+    pub fn can_detect(&self) -> bool {
+        self.distinct
+    }
+
+    /// Feed one field vector, returns a result at the end of every window
+    pub fn update(&mut self, field: [f32; 3]) -> Option<Detection> {
+        let last = self.last.replace(field)?;
+        let diff = [0, 1, 2].map(|i| field[i] - last[i]);
+        self.hz50.update(diff);
+        self.hz60.update(diff);
+        self.count += 1;
+        if self.count < self.window {
+            return None;
+        }
+
+        let n = core::mem::take(&mut self.count);
+        let amplitude_50 = self.hz50.take(n);
+        let amplitude_60 = self.hz60.take(n);
+        let mains = if !self.distinct {
+            None
+        } else if amplitude_50 > Self::DOMINANCE * amplitude_60 {
+            Some(Mains::Hz50)
+        } else if amplitude_60 > Self::DOMINANCE * amplitude_50 {
+            Some(Mains::Hz60)
+        } else {
+            None
+        };
+        Some(Detection {
+            mains,
+            amplitude_50,
+            amplitude_60,
+        })
+    }
+
+    /// Feed the field of a sample, in LSB
+    pub fn update_sample(&mut self, sample: &Sample) -> Option<Detection> {
+        self.update(sample.field.map(|v| v as f32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hum(n: u32, odr_hz: f32, hz: f32, amplitude: f32) -> [f32; 3] {
+        let t = n as f32 / odr_hz;
+        let h = amplitude * libm::sinf(2.0 * PI * hz * t);
+        [20000.0 + h, -3000.0, 40000.0 + 0.5 * h]
+    }
+
+    #[test]
+    fn test_notch() {
+        assert!((alias(50.0, 1000.0) - 50.0).abs() < 1e-3);
+        assert!((alias(60.0, 50.0) - 10.0).abs() < 1e-3);
+
+        let config = NotchConfig::default();
+        let mut filter = MainsFilter::for_mode(Mains::Hz50, 7, &config).unwrap();
+        assert_eq!(filter.frequencies().count(), 3);
+        for (f, e) in filter.frequencies().zip([50.0, 100.0, 150.0]) {
+            assert!((f - e).abs() < 1e-3);
+        }
+
+        // Fundamental and third harmonic, Earth's field passes untouched from the start
+        for n in 0..2000 {
+            let mut x = hum(n, 1000.0, 50.0, 300.0);
+            let third = hum(n, 1000.0, 150.0, 100.0);
+            x[0] += third[0] - 20000.0;
+            let y = filter.process(x);
+            if n == 0 || n > 500 {
+                assert!((y[0] - 20000.0).abs() < 2.0, "{n} {y:?}");
+                assert!((y[1] + 3000.0).abs() < 0.1);
+                assert!((y[2] - 40000.0).abs() < 2.0);
+            }
+        }
+
+        // 10 Hz: 50 Hz and its harmonics alias to DC, 60 Hz too
+        assert_eq!(
+            MainsFilter::new(Mains::Hz50, 10.0, &config)
+                .unwrap()
+                .frequencies()
+                .count(),
+            0
+        );
+        // 100 Hz: 50 at Nyquist, 100 at DC, 150 on top of 50
+        let mut filter = MainsFilter::new(Mains::Hz50, 100.0, &config).unwrap();
+        assert_eq!(filter.frequencies().count(), 1);
+        for n in 0..1000 {
+            let h = if n % 2 == 0 { 100.0 } else { -100.0 };
+            let y = filter.process([1000.0 + h, 0.0, 0.0]);
+            if n > 300 {
+                assert!((y[0] - 1000.0).abs() < 1.0, "{n} {y:?}");
+            }
+        }
+
+        for odr in [0.0, -100.0, f32::NAN, f32::INFINITY] {
+            assert_eq!(
+                MainsFilter::new(Mains::Hz50, odr, &config).err(),
+                Some(ConfigError::OutputDataRate)
+            );
+        }
+        for q in [0.0, -1.0, f32::NAN] {
+            assert_eq!(
+                MainsFilter::new(Mains::Hz60, 100.0, &NotchConfig { harmonics: 3, q }).err(),
+                Some(ConfigError::Q)
+            );
+        }
+        assert_eq!(
+            MainsFilter::for_mode(Mains::Hz50, 9, &config).err(),
+            Some(ConfigError::Mode)
+        );
+    }
+
+    #[test]
+    fn test_detector() {
+        let mut det = MainsDetector::new(200.0, 400).unwrap();
+        assert!(det.can_detect());
+        let mut result = None;
+        for n in 0..=400 {
+            result = det.update(hum(n, 200.0, 60.0, 50.0)).or(result);
+        }
+        let result = result.unwrap();
+        assert_eq!(result.mains, Some(Mains::Hz60));
+        assert!((result.amplitude_60 - 75.0).abs() < 5.0, "{result:?}");
+
+        // Aliased: 50 Hz appears at 0 Hz at 10 Hz ODR
+        assert!(!MainsDetector::new(10.0, 100).unwrap().can_detect());
+        // 100 Hz ODR: 50 Hz at 50 Hz, 60 Hz at 40 Hz
+        let mut det = MainsDetector::new(100.0, 500).unwrap();
+        let mut result = None;
+        for n in 0..=500 {
+            result = det.update(hum(n, 100.0, 50.0, 50.0)).or(result);
+        }
+        assert_eq!(result.unwrap().mains, Some(Mains::Hz50));
+
+        assert_eq!(
+            MainsDetector::new(f32::NAN, 100).err(),
+            Some(ConfigError::OutputDataRate)
+        );
+        assert_eq!(
+            MainsDetector::new(0.0, 100).err(),
+            Some(ConfigError::OutputDataRate)
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use crate::ll::reg::{ST1, ST2, TMPS};
+use crate::Measurement;
+
+/// One decoded measurement of the AK09940A
+///
+/// The magnetic field is kept in sensor counts, see [`Sample::NANO_TESLA_PER_LSB`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sample {
+    pub st1: ST1,
+    /// Magnetic field X, Y, Z in LSB
+    pub field: [i32; 3],
+    pub tmps: TMPS,
+    pub st2: ST2,
+}
+
+impl Sample {
+    /// Sensitivity of the magnetic data
+    pub const NANO_TESLA_PER_LSB: i32 = 10;
+
+    /// Magnetic field X, Y, Z in nT
+    pub fn nano_tesla(&self) -> [i32; 3] {
+        self.field.map(|h| h * Self::NANO_TESLA_PER_LSB)
+    }
+
+    /// Die temperature in milli-Celsius, e.g. 25000 at 25 °C
+    ///
+    /// Only meaningful with the temperature measurement enabled, see
+    /// `AK09940A::with_temperature`.
+    pub fn milli_celsius(&self) -> i32 {
+        self.tmps.milli_celsius()
+    }
+
+    /// Whether the sensor flagged the data as invalid (magnetic sensor overflow)
+    pub fn is_invalid(&self) -> bool {
+        self.st2.invalid_data()
+    }
+}
+
+impl From<Measurement> for Sample {
+    fn from((st1, hx, hy, hz, tmps, st2): Measurement) -> Self {
+        Self {
+            st1,
+            field: [hx.magnitude(), hy.magnitude(), hz.magnitude()],
+            tmps,
+            st2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_milli_celsius() {
+        // TMPS = (30 - T) * 1.7, so 25 °C reads 8.5 and 0 °C reads 51
+        let sample = |tmps: u8| Sample {
+            st1: ST1::new_with_raw_value(0x01),
+            field: [0; 3],
+            tmps: TMPS::new_with_raw_value(tmps),
+            st2: ST2::new_with_raw_value(0x00),
+        };
+        assert_eq!(sample(0).milli_celsius(), 30000);
+        assert_eq!(sample(51).milli_celsius(), 1);
+        assert!((sample(9).milli_celsius() - 25000).abs() < 600);
+        assert_eq!(sample(-17i8 as u8).milli_celsius(), 39999);
+    }
+}
@@ -0,0 +1,269 @@
+//! Interrupt-driven producer/consumer split
+//!
+//! [`Channel::split`] turns a `blocking` driver into a [`Producer`], which does the bus read
+//! from the DRDY interrupt handler, and a [`Consumer`] for the main loop, connected by a
+//! `heapless::spsc::Queue` of samples. The channel usually lives in a `static`.
+//!
+//! Every interrupt drains the sensor until `ST1.DRDY` clears, so with the FIFO enabled a
+//! single interrupt can enqueue several samples. When the queue is full the new sample is
+//! dropped and counted as an overflow. Jumps in the `ST1` frame number are counted as well,
+//! they show frames the sensor produced but the interrupt handler never read.
+
+use crate::blocking::{Error, AK09940A};
+use crate::ll::NoDelay;
+use crate::sample::Sample;
+use crate::stream::FIFO_DEPTH;
+use core::sync::atomic::{AtomicU32, Ordering};
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::SpiDevice;
+use heapless::spsc::{self, Queue};
+
+/// `ST1.FNUM` wraps after this many frames
+const FRAME_NUMBERS: u8 = 16;
+
+/// Counters shared by the producer and the consumer
+///
+/// Only the producer writes them, so plain loads and stores suffice, also on targets without
+/// atomic read-modify-write.
+#[derive(Debug, Default)]
+struct Counters {
+    overflows: AtomicU32,
+    gaps: AtomicU32,
+    lost_frames: AtomicU32,
+}
+
+impl Counters {
+    fn add(counter: &AtomicU32, n: u32) {
+        counter.store(
+            counter.load(Ordering::Relaxed).wrapping_add(n),
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Snapshot of the counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Samples dropped because the queue was full
+    pub overflows: u32,
+    /// Number of jumps in the frame number
+    pub gaps: u32,
+    /// Frames skipped by those jumps, modulo 16 per jump
+    pub lost_frames: u32,
+}
+
+/// Queue and counters, holds up to `N - 1` samples
+pub struct Channel<const N: usize> {
+    queue: Queue<Sample, N>,
+    counters: Counters,
+}
+
+impl<const N: usize> Default for Channel<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Channel<N> {
+    pub const fn new() -> Self {
+        Self {
+            queue: Queue::new(),
+            counters: Counters {
+                overflows: AtomicU32::new(0),
+                gaps: AtomicU32::new(0),
+                lost_frames: AtomicU32::new(0),
+            },
+        }
+    }
+
+    /// Split into the interrupt side, owning the driver, and the application side
+    pub fn split<DEV, State, B>(
+        &mut self,
+        driver: AK09940A<DEV, State, B>,
+    ) -> (Producer<'_, DEV, State, N, B>, Consumer<'_, N>)
+    where
+        DEV: SpiDevice,
+    {
+        let (tx, rx) = self.queue.split();
+        (
+            Producer {
+                driver,
+                queue: tx,
+                counters: &self.counters,
+                last_frame: None,
+            },
+            Consumer {
+                queue: rx,
+                counters: &self.counters,
+            },
+        )
+    }
+}
+
+/// Interrupt side, reads the sensor and enqueues the samples
+pub struct Producer<'a, DEV, State, const N: usize, B = NoDelay>
+where
+    DEV: SpiDevice,
+{
+    driver: AK09940A<DEV, State, B>,
+    queue: spsc::Producer<'a, Sample, N>,
+    counters: &'a Counters,
+    last_frame: Option<u8>,
+}
+
+impl<DEV, State, const N: usize, B> Producer<'_, DEV, State, N, B>
+where
+    DEV: SpiDevice,
+    B: DelayNs,
+{
+    /// Read the pending samples and enqueue them, call from the DRDY interrupt handler
+    ///
+    /// Reads until `ST1.DRDY` clears, at most the FIFO depth of 8 frames. Returns the number of
+    /// samples enqueued, samples dropped because the queue is full are counted in
+    /// [`Stats::overflows`].
+    pub fn on_data_ready(&mut self) -> Result<usize, Error<DEV>> {
+        let mut enqueued = 0;
+        for _ in 0..FIFO_DEPTH {
+            let sample = self.driver.read_sample()?;
+            if !sample.st1.data_ready() {
+                break;
+            }
+
+            let frame = sample.st1.frame_number().value();
+            if let Some(last) = self.last_frame {
+                let skipped = (frame + FRAME_NUMBERS - last - 1) % FRAME_NUMBERS;
+                if skipped != 0 {
+                    Counters::add(&self.counters.gaps, 1);
+                    Counters::add(&self.counters.lost_frames, skipped as u32);
+                }
+            }
+            self.last_frame = Some(frame);
+
+            if self.queue.enqueue(sample).is_err() {
+                Counters::add(&self.counters.overflows, 1);
+            } else {
+                enqueued += 1;
+            }
+        }
+        Ok(enqueued)
+    }
+
+    /// The driver, e.g. to change settings between interrupts
+    pub fn driver(&mut self) -> &mut AK09940A<DEV, State, B> {
+        &mut self.driver
+    }
+
+    /// Give back the driver
+    pub fn into_driver(self) -> AK09940A<DEV, State, B> {
+        self.driver
+    }
+}
+
+/// Application side
+pub struct Consumer<'a, const N: usize> {
+    queue: spsc::Consumer<'a, Sample, N>,
+    counters: &'a Counters,
+}
+
+impl<const N: usize> Consumer<'_, N> {
+    /// Oldest queued sample
+    pub fn dequeue(&mut self) -> Option<Sample> {
+        self.queue.dequeue()
+    }
+
+    /// Number of queued samples
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.len() == 0
+    }
+
+    /// Counters since the split
+    pub fn stats(&self) -> Stats {
+        Stats {
+            overflows: self.counters.overflows.load(Ordering::Relaxed),
+            gaps: self.counters.gaps.load(Ordering::Relaxed),
+            lost_frames: self.counters.lost_frames.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::frame;
+    use embedded_hal_mock::eh1::spi::{Mock, Transaction};
+
+    /// Block read of one frame with frame number `fnum`, also used as HX
+    fn numbered(fnum: u8) -> [Transaction<u8>; 3] {
+        frame((fnum << 1) | 0x01, fnum.into())
+    }
+
+    #[test]
+    fn overflows_and_gaps() {
+        let expectations = [
+            // Spurious interrupt
+            frame(0x00, 0),
+            // Two frames in the FIFO
+            numbered(14),
+            numbered(15),
+            frame(0x00, 0),
+            numbered(1),
+            frame(0x00, 0),
+            numbered(2),
+            frame(0x00, 0),
+        ]
+        .concat();
+        let mut spi = Mock::new(&expectations);
+
+        let mut channel = Channel::<3>::new();
+        let (mut producer, mut consumer) = channel.split(AK09940A::new(spi.clone()));
+        assert!(consumer.is_empty());
+
+        assert_eq!(producer.on_data_ready().unwrap(), 0);
+        assert_eq!(producer.on_data_ready().unwrap(), 2);
+        // Frame 0 was never read, and the queue holds two samples
+        assert_eq!(producer.on_data_ready().unwrap(), 0);
+        assert_eq!(
+            consumer.stats(),
+            Stats {
+                overflows: 1,
+                gaps: 1,
+                lost_frames: 1
+            }
+        );
+
+        assert_eq!(consumer.len(), 2);
+        assert_eq!(consumer.dequeue().unwrap().field[0], 14);
+        assert_eq!(producer.on_data_ready().unwrap(), 1);
+        assert_eq!(consumer.dequeue().unwrap().field[0], 15);
+        assert_eq!(consumer.dequeue().unwrap().field[0], 2);
+        assert_eq!(consumer.dequeue(), None);
+        assert_eq!(consumer.stats().gaps, 1);
+
+        producer.into_driver();
+        spi.done();
+    }
+
+    #[test]
+    fn reads_bounded_by_fifo_depth() {
+        // DRDY stays set, e.g. the sensor produces frames as fast as they are read
+        let frames: std::vec::Vec<_> = (0..9).flat_map(numbered).collect();
+        let expectations = [&frames[..], &frame(0x00, 0)].concat();
+        let mut spi = Mock::new(&expectations);
+
+        let mut channel = Channel::<16>::new();
+        let (mut producer, consumer) = channel.split(AK09940A::new(spi.clone()));
+        assert_eq!(producer.on_data_ready().unwrap(), FIFO_DEPTH);
+        assert_eq!(producer.on_data_ready().unwrap(), 1);
+        assert_eq!(consumer.len(), 9);
+        assert_eq!(consumer.stats(), Stats::default());
+
+        producer.into_driver();
+        spi.done();
+    }
+}
@@ -13,8 +13,21 @@ pub struct Continuous;
 pub struct SingleShot;
 
 /// Indicates that the `AK09940A` instance is in external trigger mode
+///
+/// Holds a [`TriggerPin`] when the driver generates the trigger pulses itself.
+#[derive(Debug)]
+pub struct ExternalTrigger<P = NoPin>(pub(crate) P);
+
+/// No TRG pin, the trigger pulses come from elsewhere
+#[derive(Debug)]
+pub struct NoPin;
+
+/// TRG pin and the delay timing its pulses
 #[derive(Debug)]
-pub struct ExternalTrigger;
+pub struct TriggerPin<P, D> {
+    pub(crate) pin: P,
+    pub(crate) delay: D,
+}
 
 /// Indicates that the `AK09940A` instance is in self-test mode
 #[derive(Debug)]
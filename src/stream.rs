@@ -0,0 +1,348 @@
+//! Async sample streams for continuous and external trigger mode
+//!
+//! `samples()` borrows a `non_blocking` driver and yields fresh samples from
+//! [`SampleStream::next`], waiting for data ready in between. Data ready is signalled either by
+//! the DRDY pin ([`DrdyPin`]) or, when the pin is not wired or is used as TRG in external
+//! trigger mode, by polling the `ST` register ([`PollStatus`]).
+//!
+//! With the FIFO enabled (`with_fifo_watermark`) every wake-up reads the frames up to the
+//! watermark, one bus transaction per frame, and queues them. With a clock the frames are
+//! stamped as they are read, see [`crate::time`].
+//!
+//! # Cancel safety
+//!
+//! Dropping a pending `next()` loses no frames: while it waits for data ready nothing has
+//! been read yet, and every frame read is queued before the next await point, to be returned
+//! by the following `next()`. The only await point that touches the sensor data is the
+//! `SpiDevice` transaction of a single frame; whether dropping it midway ends the transaction
+//! cleanly is up to the `SpiDevice` implementation.
+
+use crate::ll::non_blocking::LL;
+use crate::ll::{reg, NoDelay};
+use crate::non_blocking::{Error, AK09940A};
+use crate::sample::Sample;
+use crate::states::{Continuous, ExternalTrigger};
+use crate::time::{Clock, NoClock, Timestamped, Timestamper};
+use core::future::Future;
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::spi::SpiDevice;
+use heapless::Deque;
+
+/// Number of frames the AK09940A FIFO holds
+pub const FIFO_DEPTH: usize = 8;
+
+/// Source of the data ready signal
+pub trait DataReady<DEV, B = NoDelay>
+where
+    DEV: SpiDevice,
+{
+    /// Wait until data is ready (or the FIFO watermark is reached)
+    ///
+    /// Must be cancel-safe and must not consume data.
+    fn wait(&mut self, ll: &mut LL<DEV, B>) -> impl Future<Output = Result<(), Error<DEV>>>;
+}
+
+/// The DRDY pin, active high
+pub struct DrdyPin<P>(pub P);
+
+impl<DEV, B, P> DataReady<DEV, B> for DrdyPin<P>
+where
+    DEV: SpiDevice,
+    P: Wait,
+{
+    async fn wait(&mut self, _ll: &mut LL<DEV, B>) -> Result<(), Error<DEV>> {
+        // Level, not edge: data that became ready while nobody was waiting is not missed
+        self.0.wait_for_high().await.map_err(|_| Error::Pin)
+    }
+}
+
+/// Poll `ST.DRDY` every `interval_us` microseconds
+pub struct PollStatus<D> {
+    pub delay: D,
+    pub interval_us: u32,
+}
+
+impl<DEV, B, D> DataReady<DEV, B> for PollStatus<D>
+where
+    DEV: SpiDevice,
+    B: DelayNs,
+    D: DelayNs,
+{
+    async fn wait(&mut self, ll: &mut LL<DEV, B>) -> Result<(), Error<DEV>> {
+        loop {
+            let st = ll.read::<reg::ST>().await.map_err(Error::Spi)?;
+            if st.data_ready() {
+                return Ok(());
+            }
+            self.delay.delay_us(self.interval_us).await;
+        }
+    }
+}
+
+/// Stream of samples, see the [module documentation](self)
+///
+/// Samples are stamped with the clock given to [`with_clock`](SampleStream::with_clock),
+/// the frames of a FIFO burst relative to the data ready wake-up.
+pub struct SampleStream<'a, DEV, State, W, C = NoClock, B = NoDelay>
+where
+    DEV: SpiDevice,
+{
+    driver: &'a mut AK09940A<DEV, State, B>,
+    ready: W,
+    stamper: Timestamper<C>,
+    pending: Deque<Timestamped, FIFO_DEPTH>,
+}
+
+impl<'a, DEV, State, W, B> SampleStream<'a, DEV, State, W, NoClock, B>
+where
+    DEV: SpiDevice,
+    W: DataReady<DEV, B>,
+{
+    fn new(driver: &'a mut AK09940A<DEV, State, B>, ready: W) -> Self {
+        Self {
+            driver,
+            ready,
+            stamper: Timestamper::new(NoClock, 0),
+            pending: Deque::new(),
+        }
+    }
+
+    /// Stamp the samples using `clock`, with `period_ns` the output data period
+    pub fn with_clock<C: Clock>(
+        self,
+        clock: C,
+        period_ns: u32,
+    ) -> SampleStream<'a, DEV, State, W, C, B> {
+        SampleStream {
+            driver: self.driver,
+            ready: self.ready,
+            stamper: Timestamper::new(clock, period_ns),
+            pending: self.pending,
+        }
+    }
+}
+
+impl<DEV, State, W, C, B> SampleStream<'_, DEV, State, W, C, B>
+where
+    DEV: SpiDevice,
+    W: DataReady<DEV, B>,
+    C: Clock,
+    B: DelayNs,
+{
+    /// Next fresh sample
+    ///
+    /// On a bus error the frames read before it are kept and returned by the following calls.
+    pub async fn next(&mut self) -> Result<Sample, Error<DEV>> {
+        self.next_timestamped().await.map(|t| t.sample)
+    }
+
+    /// Next fresh sample with the time it was measured
+    pub async fn next_timestamped(&mut self) -> Result<Timestamped, Error<DEV>> {
+        loop {
+            if let Some(sample) = self.pending.pop_front() {
+                return Ok(sample);
+            }
+            self.ready.wait(&mut self.driver.dev).await?;
+
+            let frames = self.driver.fifo_watermark().map_or(1, |wm| wm + 1);
+            let mut burst = self.stamper.begin_burst(frames);
+            for _ in 0..frames {
+                let sample = self.driver.read_sample().await?;
+                // FIFO drained, or a spurious wake-up
+                if !sample.st1.data_ready() {
+                    break;
+                }
+                // Cannot be full, it is only filled while empty and frames <= FIFO_DEPTH
+                let _ = self.pending.push_back(burst.stamp(sample));
+            }
+        }
+    }
+
+    /// Number of samples read from the sensor but not yet returned
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// The timestamper, e.g. to correct the output data period
+    pub fn timestamper(&mut self) -> &mut Timestamper<C> {
+        &mut self.stamper
+    }
+
+    /// End the stream, giving back the data ready source
+    ///
+    /// Samples still pending are dropped.
+    pub fn into_ready(self) -> W {
+        self.ready
+    }
+}
+
+impl<DEV, B> AK09940A<DEV, Continuous, B>
+where
+    DEV: SpiDevice,
+{
+    /// Stream of samples, woken by `ready`
+    pub fn samples<W: DataReady<DEV, B>>(
+        &mut self,
+        ready: W,
+    ) -> SampleStream<'_, DEV, Continuous, W, NoClock, B> {
+        SampleStream::new(self, ready)
+    }
+}
+
+impl<DEV, P, B> AK09940A<DEV, ExternalTrigger<P>, B>
+where
+    DEV: SpiDevice,
+{
+    /// Stream of samples, woken by `ready`
+    ///
+    /// The DRDY/TRG pin is the trigger input in this mode, use [`PollStatus`].
+    pub fn samples<W: DataReady<DEV, B>>(
+        &mut self,
+        ready: W,
+    ) -> SampleStream<'_, DEV, ExternalTrigger<P>, W, NoClock, B> {
+        SampleStream::new(self, ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use crate::test_util::{frame, reset, transfer};
+    use core::task::{Context, Poll, Waker};
+    use embedded_hal_mock::eh1::digital::{
+        Mock as PinMock, State as PinState, Transaction as PinTransaction,
+    };
+    use embedded_hal_mock::eh1::spi::Mock;
+    use std::vec::Vec;
+
+    #[async_std::test]
+    async fn fifo_frames_are_queued() {
+        let expectations = [
+            // Reset, identify
+            &reset(0x48)[..],
+            // Watermark 2, then continuous mode 3 with FIFO
+            &transfer([0xB0, 0x00], [0x00, 0x00]),
+            &transfer([0x30, 0x02], [0x00, 0x00]),
+            &transfer([0xB2, 0x00], [0x00, 0x00]),
+            &transfer([0x32, 0x83], [0x00, 0x00]),
+            // Three frames after the first wake-up
+            &frame(0x03, 1),
+            &frame(0x05, 2),
+            &frame(0x07, 3),
+            // Second wake-up drains after one frame
+            &frame(0x09, 4),
+            &frame(0x00, 0),
+        ]
+        .concat();
+        let mut spi = Mock::new(&expectations);
+        let mut pin = PinMock::new(&[
+            PinTransaction::wait_for_state(PinState::High),
+            PinTransaction::wait_for_state(PinState::High),
+        ]);
+
+        let mut mag = AK09940A::new(spi.clone())
+            .with_fifo_watermark(Some(2))
+            .continuous(3)
+            .await
+            .unwrap();
+        let mut stream = mag.samples(DrdyPin(pin.clone()));
+        for hx in 1..=3 {
+            assert_eq!(stream.next().await.unwrap().field[0], hx);
+            assert_eq!(stream.pending(), 3 - hx as usize);
+        }
+        assert_eq!(stream.next().await.unwrap().field[0], 4);
+        assert_eq!(stream.pending(), 0);
+        drop(stream);
+
+        spi.done();
+        pin.done();
+    }
+
+    /// Forwards to the mock, except that the transaction after the first `pass` yields once
+    struct Yielding {
+        spi: Mock<u8>,
+        pass: Option<usize>,
+    }
+
+    impl embedded_hal::spi::ErrorType for Yielding {
+        type Error = embedded_hal::spi::ErrorKind;
+    }
+
+    impl SpiDevice for Yielding {
+        async fn transaction(
+            &mut self,
+            operations: &mut [embedded_hal::spi::Operation<'_, u8>],
+        ) -> Result<(), Self::Error> {
+            match self.pass {
+                Some(0) => {
+                    self.pass = None;
+                    let mut yielded = false;
+                    core::future::poll_fn(|cx| {
+                        if yielded {
+                            return Poll::Ready(());
+                        }
+                        yielded = true;
+                        cx.waker().wake_by_ref();
+                        Poll::Pending
+                    })
+                    .await;
+                }
+                Some(n) => self.pass = Some(n - 1),
+                None => {}
+            }
+            self.spi.transaction(operations).await
+        }
+    }
+
+    #[test]
+    fn dropped_next_loses_nothing() {
+        let expectations = [
+            &reset(0x48)[..],
+            &transfer([0xB0, 0x00], [0x00, 0x00]),
+            &transfer([0x30, 0x02], [0x00, 0x00]),
+            &transfer([0xB2, 0x00], [0x00, 0x00]),
+            &transfer([0x32, 0x83], [0x00, 0x00]),
+            // First frame of the burst, then the cancelled read of the second one
+            &frame(0x03, 1),
+            // The next wake-up reads a full burst
+            &frame(0x05, 2),
+            &frame(0x07, 3),
+            &frame(0x09, 4),
+        ]
+        .concat();
+        let mut spi = Mock::new(&expectations);
+        let mut pin = PinMock::new(&[
+            PinTransaction::wait_for_state(PinState::High),
+            PinTransaction::wait_for_state(PinState::High),
+        ]);
+
+        let mag = AK09940A::new(Yielding {
+            spi: spi.clone(),
+            pass: None,
+        })
+        .with_fifo_watermark(Some(2));
+        let mut mag = async_std::task::block_on(mag.continuous(3)).unwrap();
+        mag.dev.dev.pass = Some(1);
+
+        let mut stream = mag.samples(DrdyPin(pin.clone()));
+        {
+            let mut next = core::pin::pin!(stream.next());
+            let mut cx = Context::from_waker(Waker::noop());
+            assert!(next.as_mut().poll(&mut cx).is_pending());
+        }
+        assert_eq!(stream.pending(), 1);
+
+        let fields: Vec<_> = (0..4)
+            .map(|_| async_std::task::block_on(stream.next()).unwrap().field[0])
+            .collect();
+        assert_eq!(fields, [1, 2, 3, 4]);
+        assert_eq!(stream.pending(), 0);
+        drop(stream);
+
+        spi.done();
+        pin.done();
+    }
+}
@@ -0,0 +1,97 @@
+//! SPI mock helpers shared by the unit tests
+
+extern crate std;
+
+use embedded_hal::spi::{ErrorKind, ErrorType, Operation};
+use embedded_hal_mock::eh1::spi::{Mock, Transaction};
+use std::vec;
+use std::vec::Vec;
+
+/// Single register write or read
+pub(crate) fn transfer(expected: [u8; 2], response: [u8; 2]) -> [Transaction<u8>; 3] {
+    [
+        Transaction::transaction_start(),
+        Transaction::transfer_in_place(expected.to_vec(), response.to_vec()),
+        Transaction::transaction_end(),
+    ]
+}
+
+pub(crate) fn delay(ns: u32) -> [Transaction<u8>; 3] {
+    [
+        Transaction::transaction_start(),
+        Transaction::delay(ns),
+        Transaction::transaction_end(),
+    ]
+}
+
+/// Block read of one frame starting at ST1
+pub(crate) fn block(response: [u8; 12]) -> [Transaction<u8>; 3] {
+    [
+        Transaction::transaction_start(),
+        Transaction::transfer(vec![0x90], response.to_vec()),
+        Transaction::transaction_end(),
+    ]
+}
+
+/// Block read of one frame with only HX set
+pub(crate) fn frame(st1: u8, hx: i32) -> [Transaction<u8>; 3] {
+    let mut response = [0x00; 12];
+    response[0] = st1;
+    response[1..4].copy_from_slice(&hx.to_le_bytes()[..3]);
+    block(response)
+}
+
+/// Soft reset followed by the company ID check
+pub(crate) fn reset(wia1: u8) -> Vec<Transaction<u8>> {
+    [
+        &transfer([0x33, 0x01], [0x00, 0x00])[..],
+        &delay(100000),
+        &transfer([0x80, 0x00], [0x00, wia1]),
+    ]
+    .concat()
+}
+
+/// Forwards the first `pass` transactions to the mock, fails the next `failures`, then
+/// forwards the rest
+#[derive(Debug)]
+pub(crate) struct Flaky {
+    pub spi: Mock<u8>,
+    pub pass: usize,
+    pub failures: usize,
+}
+
+impl Flaky {
+    fn fail(&mut self) -> bool {
+        if self.pass > 0 {
+            self.pass -= 1;
+            false
+        } else if self.failures > 0 {
+            self.failures -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl ErrorType for Flaky {
+    type Error = ErrorKind;
+}
+
+impl embedded_hal::spi::SpiDevice for Flaky {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), ErrorKind> {
+        if self.fail() {
+            return Err(ErrorKind::Other);
+        }
+        embedded_hal::spi::SpiDevice::transaction(&mut self.spi, operations)
+    }
+}
+
+impl embedded_hal_async::spi::SpiDevice for Flaky {
+    async fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), ErrorKind> {
+        if self.fail() {
+            return Err(ErrorKind::Other);
+        }
+        embedded_hal_async::spi::SpiDevice::transaction(&mut self.spi, operations).await
+    }
+}
@@ -0,0 +1,362 @@
+//! Sample timestamps
+//!
+//! A [`Clock`] provides the host's monotonic time. A single sample is stamped with the time it
+//! was read. For a FIFO burst only one instant is known, usually the data ready interrupt or the
+//! start of the read; [`Burst`] reconstructs the times of the other frames from it, the output
+//! data period and the `ST1` frame number sequence.
+//!
+//! The sensor's oscillator is not exactly at the nominal rate. [`OdrEstimator`] measures the
+//! actual output data period from host timestamps of data ready events, tracks its slow drift,
+//! and provides the correction for [`Timestamper::set_period_ns`] and for filters designed at
+//! the nominal rate.
+
+use crate::sample::Sample;
+
+/// Monotonic time source
+pub trait Clock {
+    /// Current time in microseconds, must not go backwards
+    fn now_us(&mut self) -> u64;
+}
+
+impl<F> Clock for F
+where
+    F: FnMut() -> u64,
+{
+    fn now_us(&mut self) -> u64 {
+        self()
+    }
+}
+
+/// Clock that is always 0, for streams without timestamps
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoClock;
+
+impl Clock for NoClock {
+    fn now_us(&mut self) -> u64 {
+        0
+    }
+}
+
+/// Nominal output data rate of continuous mode `mode` (1..=8) in Hz
+pub const fn nominal_odr_hz(mode: u8) -> Option<u32> {
+    match mode {
+        1 => Some(10),
+        2 => Some(20),
+        3 => Some(50),
+        4 => Some(100),
+        5 => Some(200),
+        6 => Some(400),
+        7 => Some(1000),
+        8 => Some(2500),
+        _ => None,
+    }
+}
+
+/// Nominal output data period of continuous mode `mode` (1..=8) in ns
+pub const fn nominal_period_ns(mode: u8) -> Option<u32> {
+    match nominal_odr_hz(mode) {
+        Some(hz) => Some(1_000_000_000 / hz),
+        None => None,
+    }
+}
+
+/// A sample with the time it was measured
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamped {
+    pub sample: Sample,
+    /// Host time in microseconds
+    pub timestamp_us: u64,
+}
+
+/// Clock and output data period
+#[derive(Debug, Clone)]
+pub struct Timestamper<C> {
+    clock: C,
+    period_ns: u32,
+}
+
+impl<C> Timestamper<C>
+where
+    C: Clock,
+{
+    /// `period_ns` is the output data period, e.g. from [`nominal_period_ns`]
+    pub fn new(clock: C, period_ns: u32) -> Self {
+        Self { clock, period_ns }
+    }
+
+    pub fn now_us(&mut self) -> u64 {
+        self.clock.now_us()
+    }
+
+    pub fn period_ns(&self) -> u32 {
+        self.period_ns
+    }
+
+    /// Replace the output data period, e.g. with a measured one
+    pub fn set_period_ns(&mut self, period_ns: u32) {
+        self.period_ns = period_ns;
+    }
+
+    /// Stamp a sample with the current time
+    pub fn stamp(&mut self, sample: Sample) -> Timestamped {
+        Timestamped {
+            sample,
+            timestamp_us: self.clock.now_us(),
+        }
+    }
+
+    /// Start a burst at the current time
+    ///
+    /// `frames_at_reference` is the number of frames the FIFO held at this instant, i.e.
+    /// `watermark + 1` when called on the data ready interrupt, or the number of frames about
+    /// to be read when called right before the read.
+    pub fn begin_burst(&mut self, frames_at_reference: u8) -> Burst {
+        Burst::new(self.clock.now_us(), self.period_ns, frames_at_reference)
+    }
+}
+
+/// Reconstructs the times of the frames of one FIFO burst, oldest first
+#[derive(Debug, Clone)]
+pub struct Burst {
+    reference_us: u64,
+    period_ns: u32,
+    frames_at_reference: u8,
+    /// Frame number and offset in periods from the first frame of the last stamped frame
+    last: Option<(u8, u32)>,
+}
+
+impl Burst {
+    /// Burst whose `frames_at_reference`-th frame was measured at `reference_us`
+    pub fn new(reference_us: u64, period_ns: u32, frames_at_reference: u8) -> Self {
+        Self {
+            reference_us,
+            period_ns,
+            frames_at_reference: frames_at_reference.max(1),
+            last: None,
+        }
+    }
+
+    /// Stamp the next frame read in this burst
+    ///
+    /// The frame number decides how many periods passed since the previous frame. If it does
+    /// not advance (the sensor restarted the count), one period is assumed.
+    pub fn stamp(&mut self, sample: Sample) -> Timestamped {
+        let frame = sample.st1.frame_number().value();
+        let offset = match self.last {
+            None => 0,
+            Some((last, offset)) => {
+                let step = frame.wrapping_sub(last) & 0x0F;
+                offset + step.max(1) as u32
+            }
+        };
+        self.last = Some((frame, offset));
+
+        let periods = offset as i64 - (self.frames_at_reference as i64 - 1);
+        let delta_us = periods * self.period_ns as i64 / 1000;
+        Timestamped {
+            sample,
+            timestamp_us: self.reference_us.saturating_add_signed(delta_us),
+        }
+    }
+}
+
+/// `ST1.FNUM` wraps after this many frames
+const FRAME_NUMBERS: u32 = 16;
+
+/// Measures the actual output data period and follows its drift
+///
+/// Feed it the host time of data ready events with [`update`](OdrEstimator::update) (frame
+/// number of the frame that raised it) or [`update_frames`](OdrEstimator::update_frames)
+/// (number of frames since the previous event). Each interval is averaged into the estimate
+/// with an exponential weight of time constant `time_constant_s`, long enough to smooth the
+/// interrupt latency jitter, short enough to follow temperature drift.
+#[derive(Debug, Clone)]
+pub struct OdrEstimator {
+    nominal_period_ns: u32,
+    time_constant_s: f32,
+    max_deviation: f32,
+    /// Time and frame number of the previous event
+    last: Option<(u64, Option<u8>)>,
+    period_ns: Option<f32>,
+}
+
+impl OdrEstimator {
+    /// Intervals more than this fraction off the nominal period are rejected by default
+    pub const MAX_DEVIATION: f32 = 0.1;
+
+    /// `nominal_period_ns` as from [`nominal_period_ns`]
+    pub fn new(nominal_period_ns: u32, time_constant_s: f32) -> Self {
+        Self {
+            nominal_period_ns,
+            time_constant_s,
+            max_deviation: Self::MAX_DEVIATION,
+            last: None,
+            period_ns: None,
+        }
+    }
+
+    /// Reject intervals more than `max_deviation` (fraction) off the nominal period
+    pub fn with_max_deviation(mut self, max_deviation: f32) -> Self {
+        self.max_deviation = max_deviation;
+        self
+    }
+
+    /// Forget the measurement, e.g. after a mode change
+    pub fn reset(&mut self, nominal_period_ns: u32) {
+        self.nominal_period_ns = nominal_period_ns;
+        self.last = None;
+        self.period_ns = None;
+    }
+
+    /// Data ready at `timestamp_us` for the frame with number `frame_number`
+    ///
+    /// The frame number only counts modulo 16, the host interval decides how many wraps
+    /// happened in between. Returns whether the interval was used.
+    pub fn update(&mut self, timestamp_us: u64, frame_number: u8) -> bool {
+        let frame_number = frame_number & 0x0F;
+        let Some((last_us, Some(last_frame))) = self.last else {
+            self.last = Some((timestamp_us, Some(frame_number)));
+            return false;
+        };
+        self.last = Some((timestamp_us, Some(frame_number)));
+
+        let dt_ns = timestamp_us.saturating_sub(last_us) as f32 * 1000.0;
+        let step = (frame_number.wrapping_sub(last_frame) & 0x0F) as u32;
+        // Add the wraps that bring the count closest to the nominal expectation
+        let expected = dt_ns / self.period();
+        let wraps = libm::roundf((expected - step as f32) / FRAME_NUMBERS as f32).max(0.0);
+        self.accept(dt_ns, step + wraps as u32 * FRAME_NUMBERS)
+    }
+
+    /// Data ready at `timestamp_us`, `frames` frames after the previous event
+    ///
+    /// Returns whether the interval was used.
+    pub fn update_frames(&mut self, timestamp_us: u64, frames: u32) -> bool {
+        let last = self.last.replace((timestamp_us, None));
+        match last {
+            Some((last_us, _)) => {
+                let dt_ns = timestamp_us.saturating_sub(last_us) as f32 * 1000.0;
+                self.accept(dt_ns, frames)
+            }
+            None => false,
+        }
+    }
+
+    fn accept(&mut self, dt_ns: f32, frames: u32) -> bool {
+        if frames == 0 {
+            return false;
+        }
+        let period = dt_ns / frames as f32;
+        let nominal = self.nominal_period_ns as f32;
+        if libm::fabsf(period / nominal - 1.0) > self.max_deviation {
+            return false;
+        }
+        self.period_ns = Some(match self.period_ns {
+            None => period,
+            Some(estimate) => {
+                let weight = 1.0 - libm::expf(-dt_ns * 1e-9 / self.time_constant_s);
+                estimate + weight * (period - estimate)
+            }
+        });
+        true
+    }
+
+    fn period(&self) -> f32 {
+        self.period_ns.unwrap_or(self.nominal_period_ns as f32)
+    }
+
+    /// Measured period in ns, `None` before the first interval
+    pub fn measured_period_ns(&self) -> Option<f32> {
+        self.period_ns
+    }
+
+    /// Best known period in ns, the nominal one until measured
+    ///
+    /// For [`Timestamper::set_period_ns`].
+    pub fn period_ns(&self) -> u32 {
+        libm::roundf(self.period()) as u32
+    }
+
+    /// Best known output data rate in Hz
+    pub fn odr_hz(&self) -> f32 {
+        1e9 / self.period()
+    }
+
+    /// Actual over nominal output data rate, 1 until measured
+    ///
+    /// Scale frequencies computed at the nominal rate by it, or divide time constants by it.
+    pub fn correction(&self) -> f32 {
+        self.nominal_period_ns as f32 / self.period()
+    }
+
+    /// Deviation of the actual rate from the nominal one in parts per million
+    pub fn drift_ppm(&self) -> f32 {
+        (self.correction() - 1.0) * 1e6
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ll::reg::{ST1, ST2, TMPS};
+
+    fn sample(frame: u8) -> Sample {
+        Sample {
+            st1: ST1::new_with_raw_value((frame << 1) | 0x01),
+            field: [frame as i32; 3],
+            tmps: TMPS::new_with_raw_value(0),
+            st2: ST2::new_with_raw_value(0),
+        }
+    }
+
+    #[test]
+    fn test_burst() {
+        assert_eq!(nominal_period_ns(4), Some(10_000_000));
+        assert_eq!(nominal_period_ns(9), None);
+
+        // Watermark of 4 frames reached at t = 1 s, 100 Hz
+        let mut now = 1_000_000;
+        let mut stamper = Timestamper::new(|| now, 10_000_000);
+        let mut burst = stamper.begin_burst(4);
+        let times: [u64; 5] = [14, 15, 0, 2, 3].map(|f| burst.stamp(sample(f)).timestamp_us);
+        // Frame 1 was never read, frame 3 arrived during the read
+        assert_eq!(times, [970_000, 980_000, 990_000, 1_010_000, 1_020_000]);
+
+        now = 2_000_000;
+        let mut stamper = Timestamper::new(|| now, 10_000_000);
+        assert_eq!(stamper.stamp(sample(0)).timestamp_us, 2_000_000);
+    }
+
+    #[test]
+    fn test_odr_estimator() {
+        // 100 Hz nominal, running 0.5 % fast with ±200 µs interrupt latency
+        let actual_ns = 10_000_000.0 / 1.005;
+        let mut est = OdrEstimator::new(10_000_000, 10.0);
+        assert_eq!(est.correction(), 1.0);
+        assert_eq!(est.period_ns(), 10_000_000);
+
+        let mut frame = 0u32;
+        for i in 0..2000u32 {
+            // Every 4th frame, some events missed so the frame number wraps
+            frame += if i % 50 == 0 { 20 } else { 4 };
+            let jitter = if i % 2 == 0 { 200.0 } else { -200.0 };
+            let t = (frame as f64 * actual_ns as f64 / 1000.0 + jitter) as u64;
+            est.update(t, (frame % 16) as u8);
+        }
+        assert!(
+            (est.drift_ppm() - 5000.0).abs() < 100.0,
+            "{}",
+            est.drift_ppm()
+        );
+        assert!((est.odr_hz() - 100.5).abs() < 0.01);
+        assert!((est.period_ns() as f32 - actual_ns).abs() < 1000.0);
+
+        // Outliers are rejected
+        let mut est = OdrEstimator::new(10_000_000, 1.0);
+        assert!(!est.update_frames(0, 1));
+        assert!(est.update_frames(10_000, 1));
+        assert!(!est.update_frames(25_000, 1));
+        assert!(!est.update_frames(25_000, 0));
+        assert_eq!(est.period_ns(), 10_000_000);
+    }
+}
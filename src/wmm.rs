@@ -0,0 +1,482 @@
+//! World Magnetic Model
+//!
+//! Evaluates the spherical harmonic expansion of the geomagnetic main field from embedded
+//! coefficients, no network access needed. Gives the declination to turn a magnetic heading
+//! into a true one, and the expected field to sanity-check readings against.
+//!
+//! [`WMM2025`] is built in as the default model, valid from 2025 through 2029, and [`WMM2020`]
+//! for the dates before, [`for_year`] picks between them. Both are transcribed from the NOAA
+//! releases. Evaluating a model past its validity extrapolates the secular variation and the
+//! error grows by roughly 20 nT (0.1°) per year. A newer coefficient set, e.g. from the NOAA
+//! `WMM.COF` file, can be used by building a [`Model`] from it.
+//!
+//! Enabled by the `wmm` feature.
+
+use crate::sample::Sample;
+
+/// One Gauss coefficient pair of degree `n` and order `m`, Schmidt semi-normalized
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Coefficient {
+    pub n: u8,
+    pub m: u8,
+    /// nT
+    pub g: f32,
+    /// nT
+    pub h: f32,
+    /// nT per year
+    pub g_dot: f32,
+    /// nT per year
+    pub h_dot: f32,
+}
+
+/// A set of main field coefficients
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Model<'a> {
+    /// Epoch of the coefficients as a decimal year
+    pub epoch: f32,
+    /// End of the nominal validity as a decimal year
+    pub valid_until: f32,
+    /// Coefficients up to degree [`MAX_DEGREE`], in any order
+    pub coefficients: &'a [Coefficient],
+}
+
+/// Highest degree supported by [`Model::field`]
+pub const MAX_DEGREE: usize = 12;
+
+/// Geomagnetic reference radius in km
+const REFERENCE_RADIUS: f64 = 6371.2;
+/// WGS 84 semi-major axis in km
+const WGS84_A: f64 = 6378.137;
+/// WGS 84 flattening
+const WGS84_F: f64 = 1.0 / 298.257223563;
+
+/// Expected geomagnetic field at a point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Field {
+    /// North, east and down components in nT
+    pub ned: [f32; 3],
+    /// Declination in degrees, positive east of true north
+    pub declination: f32,
+    /// Inclination in degrees, positive down
+    pub inclination: f32,
+    /// Horizontal intensity in nT
+    pub horizontal: f32,
+    /// Total intensity in nT
+    pub total: f32,
+}
+
+impl Field {
+    /// North, east and down components in sensor LSB
+    pub fn ned_lsb(&self) -> [f32; 3] {
+        self.ned.map(|v| v / Sample::NANO_TESLA_PER_LSB as f32)
+    }
+
+    /// Total intensity in sensor LSB, comparable with the radius of a calibration fit
+    pub fn total_lsb(&self) -> f32 {
+        self.total / Sample::NANO_TESLA_PER_LSB as f32
+    }
+
+    /// Turn a magnetic heading into a true heading, both in degrees
+    pub fn true_heading(&self, magnetic_heading: f32) -> f32 {
+        let heading = (magnetic_heading + self.declination) % 360.0;
+        if heading < 0.0 {
+            heading + 360.0
+        } else {
+            heading
+        }
+    }
+}
+
+impl Default for Model<'static> {
+    /// The latest built-in model, [`WMM2025`]
+    fn default() -> Self {
+        WMM2025
+    }
+}
+
+impl Model<'_> {
+    /// Whether `year` lies in the nominal validity of the model
+    pub fn is_valid_for(&self, year: f32) -> bool {
+        (self.epoch..self.valid_until).contains(&year)
+    }
+
+    /// Main field at a WGS 84 position
+    ///
+    /// `latitude` and `longitude` in degrees, `height` above the ellipsoid in km,
+    /// `year` as a decimal year, see [`decimal_year`]. Degrees above [`MAX_DEGREE`] are ignored.
+    pub fn field(&self, latitude: f32, longitude: f32, height: f32, year: f32) -> Field {
+        let lat = (latitude as f64).to_radians();
+        let lon = (longitude as f64).to_radians();
+        let height = height as f64;
+        let dt = (year - self.epoch) as f64;
+
+        // Geodetic to geocentric spherical
+        let e2 = WGS84_F * (2.0 - WGS84_F);
+        let (sin_lat, cos_lat) = (libm::sin(lat), libm::cos(lat));
+        let rc = WGS84_A / libm::sqrt(1.0 - e2 * sin_lat * sin_lat);
+        let p = (rc + height) * cos_lat;
+        let z = (rc * (1.0 - e2) + height) * sin_lat;
+        let r = libm::sqrt(p * p + z * z);
+        let lat_c = libm::asin(z / r);
+
+        // Schmidt semi-normalized associated Legendre functions of the colatitude
+        // and their derivatives
+        let ct = libm::sin(lat_c);
+        let st = libm::cos(lat_c).max(1e-10);
+        let mut pnm = [[0.0f64; MAX_DEGREE + 1]; MAX_DEGREE + 1];
+        let mut dpnm = [[0.0f64; MAX_DEGREE + 1]; MAX_DEGREE + 1];
+        pnm[0][0] = 1.0;
+        for n in 1..=MAX_DEGREE {
+            for m in 0..=n {
+                if n == m {
+                    pnm[n][m] = st * pnm[n - 1][m - 1];
+                    dpnm[n][m] = st * dpnm[n - 1][m - 1] + ct * pnm[n - 1][m - 1];
+                } else if n == 1 || m == n - 1 {
+                    pnm[n][m] = ct * pnm[n - 1][m];
+                    dpnm[n][m] = ct * dpnm[n - 1][m] - st * pnm[n - 1][m];
+                } else {
+                    let k = ((n - 1) * (n - 1) - m * m) as f64 / ((2 * n - 1) * (2 * n - 3)) as f64;
+                    pnm[n][m] = ct * pnm[n - 1][m] - k * pnm[n - 2][m];
+                    dpnm[n][m] = ct * dpnm[n - 1][m] - st * pnm[n - 1][m] - k * dpnm[n - 2][m];
+                }
+            }
+        }
+        let mut schmidt = [[0.0f64; MAX_DEGREE + 1]; MAX_DEGREE + 1];
+        schmidt[0][0] = 1.0;
+        for n in 1..=MAX_DEGREE {
+            schmidt[n][0] = schmidt[n - 1][0] * (2 * n - 1) as f64 / n as f64;
+            for m in 1..=n {
+                let j = if m == 1 { 2.0 } else { 1.0 };
+                schmidt[n][m] =
+                    schmidt[n][m - 1] * libm::sqrt((n - m + 1) as f64 * j / (n + m) as f64);
+            }
+        }
+
+        // Field in the geocentric frame
+        let ratio = REFERENCE_RADIUS / r;
+        let (mut x, mut y, mut zc) = (0.0f64, 0.0f64, 0.0f64);
+        for c in self.coefficients {
+            let (n, m) = (c.n as usize, c.m as usize);
+            if n == 0 || n > MAX_DEGREE || m > n {
+                continue;
+            }
+            let g = c.g as f64 + c.g_dot as f64 * dt;
+            let h = c.h as f64 + c.h_dot as f64 * dt;
+            let (sin_ml, cos_ml) = (libm::sin(m as f64 * lon), libm::cos(m as f64 * lon));
+            let scale = libm::pow(ratio, (n + 2) as f64) * schmidt[n][m];
+            let gh = g * cos_ml + h * sin_ml;
+            x += scale * gh * dpnm[n][m];
+            y += scale * m as f64 * (g * sin_ml - h * cos_ml) * pnm[n][m] / st;
+            zc -= scale * (n + 1) as f64 * gh * pnm[n][m];
+        }
+
+        // Rotate from geocentric to geodetic
+        let psi = lat_c - lat;
+        let north = x * libm::cos(psi) - zc * libm::sin(psi);
+        let down = x * libm::sin(psi) + zc * libm::cos(psi);
+        let east = y;
+
+        let horizontal = libm::sqrt(north * north + east * east);
+        let total = libm::sqrt(horizontal * horizontal + down * down);
+        Field {
+            ned: [north as f32, east as f32, down as f32],
+            declination: libm::atan2(east, north).to_degrees() as f32,
+            inclination: libm::atan2(down, horizontal).to_degrees() as f32,
+            horizontal: horizontal as f32,
+            total: total as f32,
+        }
+    }
+}
+
+/// Decimal year of a calendar date, e.g. 2020-07-02 is about 2020.5
+pub fn decimal_year(year: u16, month: u8, day: u8) -> f32 {
+    const DAYS_BEFORE: [u16; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+    let leap = (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400);
+    let month = month.clamp(1, 12) as usize;
+    let mut day_of_year = DAYS_BEFORE[month - 1] + day.max(1) as u16 - 1;
+    if leap && month > 2 {
+        day_of_year += 1;
+    }
+    let days = if leap { 366.0 } else { 365.0 };
+    year as f32 + day_of_year as f32 / days
+}
+
+macro_rules! coefficients {
+    ($(($n:literal, $m:literal, $g:literal, $h:literal, $gd:literal, $hd:literal)),* $(,)?) => {
+        [$(Coefficient { n: $n, m: $m, g: $g, h: $h, g_dot: $gd, h_dot: $hd }),*]
+    };
+}
+
+/// Built-in model for a decimal year, [`WMM2020`] before 2025, [`WMM2025`] from then on
+pub fn for_year(year: f32) -> Model<'static> {
+    if year < WMM2025.epoch {
+        WMM2020
+    } else {
+        WMM2025
+    }
+}
+
+/// WMM2025, epoch 2025.0, valid through 2029
+pub const WMM2025: Model<'static> = Model {
+    epoch: 2025.0,
+    valid_until: 2030.0,
+    coefficients: &WMM2025_COEFFICIENTS,
+};
+
+#[rustfmt::skip]
+const WMM2025_COEFFICIENTS: [Coefficient; 90] = coefficients![
+    (1, 0, -29351.8, 0.0, 12.0, 0.0),
+    (1, 1, -1410.8, 4545.4, 9.7, -21.5),
+    (2, 0, -2556.6, 0.0, -11.6, 0.0),
+    (2, 1, 2951.1, -3133.6, -5.2, -27.7),
+    (2, 2, 1649.3, -815.1, -8.0, -12.1),
+    (3, 0, 1361.0, 0.0, -1.3, 0.0),
+    (3, 1, -2404.1, -56.6, -4.2, 4.0),
+    (3, 2, 1243.8, 237.5, 0.4, -0.3),
+    (3, 3, 453.6, -549.5, -15.6, -4.1),
+    (4, 0, 895.0, 0.0, -1.6, 0.0),
+    (4, 1, 799.5, 278.6, -2.4, -1.1),
+    (4, 2, 55.7, -133.9, -6.0, 4.1),
+    (4, 3, -281.1, 212.0, 5.6, 1.6),
+    (4, 4, 12.1, -375.6, -7.0, -4.4),
+    (5, 0, -233.2, 0.0, 0.6, 0.0),
+    (5, 1, 368.9, 45.4, 1.4, -0.5),
+    (5, 2, 187.2, 220.2, 0.0, 2.2),
+    (5, 3, -138.7, -122.9, 0.6, 0.4),
+    (5, 4, -142.0, 43.0, 2.2, 1.7),
+    (5, 5, 20.9, 106.1, 0.9, 1.9),
+    (6, 0, 64.4, 0.0, -0.2, 0.0),
+    (6, 1, 63.8, -18.4, -0.4, 0.3),
+    (6, 2, 76.9, 16.8, 0.9, -1.6),
+    (6, 3, -115.7, 48.8, 1.2, -0.4),
+    (6, 4, -40.9, -59.8, -0.9, 0.9),
+    (6, 5, 14.9, 10.9, 0.3, 0.7),
+    (6, 6, -60.7, 72.7, 0.9, 0.9),
+    (7, 0, 79.5, 0.0, -0.0, 0.0),
+    (7, 1, -77.0, -48.9, -0.1, 0.6),
+    (7, 2, -8.8, -14.4, -0.1, 0.5),
+    (7, 3, 59.3, -1.0, 0.5, -0.8),
+    (7, 4, 15.8, 23.4, -0.1, 0.0),
+    (7, 5, 2.5, -7.4, -0.8, -1.0),
+    (7, 6, -11.1, -25.1, -0.8, 0.6),
+    (7, 7, 14.2, -2.3, 0.8, -0.2),
+    (8, 0, 23.2, 0.0, -0.1, 0.0),
+    (8, 1, 10.8, 7.1, 0.2, -0.2),
+    (8, 2, -17.5, -12.6, 0.0, 0.5),
+    (8, 3, 2.0, 11.4, 0.5, -0.4),
+    (8, 4, -21.7, -9.7, -0.1, 0.4),
+    (8, 5, 16.9, 12.7, 0.3, -0.5),
+    (8, 6, 15.0, 0.7, 0.2, -0.6),
+    (8, 7, -16.8, -5.2, -0.0, 0.3),
+    (8, 8, 0.9, 3.9, 0.2, 0.2),
+    (9, 0, 4.6, 0.0, -0.0, 0.0),
+    (9, 1, 7.8, -24.8, -0.1, -0.3),
+    (9, 2, 3.0, 12.2, 0.1, 0.3),
+    (9, 3, -0.2, 8.3, 0.3, -0.3),
+    (9, 4, -2.5, -3.3, -0.3, 0.3),
+    (9, 5, -13.1, -5.2, 0.0, 0.2),
+    (9, 6, 2.4, 7.2, 0.3, -0.1),
+    (9, 7, 8.6, -0.6, -0.1, -0.2),
+    (9, 8, -8.7, 0.8, 0.1, 0.4),
+    (9, 9, -12.9, 10.0, -0.1, 0.1),
+    (10, 0, -1.3, 0.0, 0.1, 0.0),
+    (10, 1, -6.4, 3.3, 0.0, 0.0),
+    (10, 2, 0.2, 0.0, 0.1, -0.0),
+    (10, 3, 2.0, 2.4, 0.1, -0.2),
+    (10, 4, -1.0, 5.3, -0.0, 0.1),
+    (10, 5, -0.6, -9.1, -0.3, -0.1),
+    (10, 6, -0.9, 0.4, 0.0, 0.1),
+    (10, 7, 1.5, -4.2, -0.1, 0.0),
+    (10, 8, 0.9, -3.8, -0.1, -0.1),
+    (10, 9, -2.7, 0.9, -0.0, 0.2),
+    (10, 10, -3.9, -9.1, -0.0, -0.0),
+    (11, 0, 2.9, 0.0, 0.0, 0.0),
+    (11, 1, -1.5, 0.0, -0.0, -0.0),
+    (11, 2, -2.5, 2.9, 0.0, 0.1),
+    (11, 3, 2.4, -0.6, 0.0, -0.0),
+    (11, 4, -0.6, 0.2, 0.0, 0.1),
+    (11, 5, -0.1, 0.5, -0.1, -0.0),
+    (11, 6, -0.6, -0.3, 0.0, -0.0),
+    (11, 7, -0.1, -1.2, -0.0, 0.1),
+    (11, 8, 1.1, -1.7, -0.1, -0.0),
+    (11, 9, -1.0, -2.9, -0.1, 0.0),
+    (11, 10, -0.2, -1.8, -0.1, 0.0),
+    (11, 11, 2.6, -2.3, -0.1, 0.0),
+    (12, 0, -2.0, 0.0, 0.0, 0.0),
+    (12, 1, -0.2, -1.3, 0.0, -0.0),
+    (12, 2, 0.3, 0.7, -0.0, 0.0),
+    (12, 3, 1.2, 1.0, -0.0, -0.1),
+    (12, 4, -1.3, -1.4, -0.0, 0.1),
+    (12, 5, 0.6, -0.0, -0.0, -0.0),
+    (12, 6, 0.6, 0.6, 0.1, -0.0),
+    (12, 7, 0.5, -0.1, -0.0, -0.0),
+    (12, 8, -0.1, 0.8, 0.0, 0.0),
+    (12, 9, -0.4, 0.1, 0.0, -0.0),
+    (12, 10, -0.2, -1.0, -0.1, -0.0),
+    (12, 11, -1.3, 0.1, -0.0, 0.0),
+    (12, 12, -0.7, 0.2, -0.1, -0.1),
+];
+
+/// WMM2020, epoch 2020.0, valid through 2024
+pub const WMM2020: Model<'static> = Model {
+    epoch: 2020.0,
+    valid_until: 2025.0,
+    coefficients: &WMM2020_COEFFICIENTS,
+};
+
+#[rustfmt::skip]
+const WMM2020_COEFFICIENTS: [Coefficient; 90] = coefficients![
+    (1, 0, -29404.5, 0.0, 6.7, 0.0),
+    (1, 1, -1450.7, 4652.9, 7.7, -25.1),
+    (2, 0, -2500.0, 0.0, -11.5, 0.0),
+    (2, 1, 2982.0, -2991.6, -7.1, -30.2),
+    (2, 2, 1676.8, -734.8, -2.2, -23.9),
+    (3, 0, 1363.9, 0.0, 2.8, 0.0),
+    (3, 1, -2381.0, -82.2, -6.2, 5.7),
+    (3, 2, 1236.2, 241.8, 3.4, -1.0),
+    (3, 3, 525.7, -542.9, -12.2, 1.1),
+    (4, 0, 903.1, 0.0, -1.1, 0.0),
+    (4, 1, 809.4, 282.0, -1.6, 0.2),
+    (4, 2, 86.2, -158.4, -6.0, 6.9),
+    (4, 3, -309.4, 199.8, 5.4, 3.7),
+    (4, 4, 47.9, -350.1, -5.5, -5.6),
+    (5, 0, -234.4, 0.0, -0.3, 0.0),
+    (5, 1, 363.1, 47.7, 0.6, 0.1),
+    (5, 2, 187.8, 208.4, -0.7, 2.5),
+    (5, 3, -140.7, -121.3, 0.1, -0.9),
+    (5, 4, -151.2, 32.2, 1.2, 3.0),
+    (5, 5, 13.7, 99.1, 1.0, 0.5),
+    (6, 0, 65.9, 0.0, -0.6, 0.0),
+    (6, 1, 65.6, -19.1, -0.4, 0.1),
+    (6, 2, 73.0, 25.0, 0.5, -1.8),
+    (6, 3, -121.5, 52.7, 1.4, -1.4),
+    (6, 4, -36.2, -64.4, -1.4, 0.9),
+    (6, 5, 13.5, 9.0, -0.0, 0.1),
+    (6, 6, -64.7, 68.1, 0.8, 1.0),
+    (7, 0, 80.6, 0.0, -0.1, 0.0),
+    (7, 1, -76.8, -51.4, -0.3, 0.5),
+    (7, 2, -8.3, -16.8, -0.1, 0.6),
+    (7, 3, 56.5, 2.3, 0.7, -0.7),
+    (7, 4, 15.8, 23.5, 0.2, -0.2),
+    (7, 5, 6.4, -2.2, -0.5, -1.2),
+    (7, 6, -7.2, -27.2, -0.8, 0.2),
+    (7, 7, 9.8, -1.9, 1.0, 0.3),
+    (8, 0, 23.6, 0.0, -0.1, 0.0),
+    (8, 1, 9.8, 8.4, 0.1, -0.3),
+    (8, 2, -17.5, -15.3, -0.1, 0.7),
+    (8, 3, -0.4, 12.8, 0.5, -0.2),
+    (8, 4, -21.1, -11.8, -0.1, 0.5),
+    (8, 5, 15.3, 14.9, 0.4, -0.3),
+    (8, 6, 13.7, 3.6, 0.5, -0.5),
+    (8, 7, -16.5, -6.9, 0.0, 0.4),
+    (8, 8, -0.3, 2.8, 0.4, 0.1),
+    (9, 0, 5.0, 0.0, -0.1, 0.0),
+    (9, 1, 8.2, -23.3, -0.2, -0.3),
+    (9, 2, 2.9, 11.1, -0.0, 0.2),
+    (9, 3, -1.4, 9.8, 0.4, -0.4),
+    (9, 4, -1.1, -5.1, -0.3, 0.4),
+    (9, 5, -13.3, -6.2, -0.0, 0.1),
+    (9, 6, 1.1, 7.8, 0.3, -0.0),
+    (9, 7, 8.9, 0.4, -0.0, -0.2),
+    (9, 8, -9.3, -1.5, -0.0, 0.5),
+    (9, 9, -11.9, 9.7, -0.4, 0.2),
+    (10, 0, -1.9, 0.0, 0.0, 0.0),
+    (10, 1, -6.2, 3.4, -0.0, -0.0),
+    (10, 2, -0.1, -0.2, -0.0, 0.1),
+    (10, 3, 1.7, 3.5, 0.2, -0.3),
+    (10, 4, -0.9, 4.8, -0.1, 0.1),
+    (10, 5, 0.6, -8.6, -0.2, -0.2),
+    (10, 6, -0.9, -0.1, -0.0, 0.1),
+    (10, 7, 1.9, -4.2, -0.1, -0.0),
+    (10, 8, 1.4, -3.4, -0.2, -0.1),
+    (10, 9, -2.4, -0.1, -0.1, 0.2),
+    (10, 10, -3.9, -8.8, -0.0, -0.0),
+    (11, 0, 3.0, 0.0, -0.0, 0.0),
+    (11, 1, -1.4, -0.0, -0.1, -0.0),
+    (11, 2, -2.5, 2.6, -0.0, 0.1),
+    (11, 3, 2.4, -0.5, 0.0, 0.0),
+    (11, 4, -0.9, -0.4, -0.0, 0.2),
+    (11, 5, 0.3, 0.6, -0.1, -0.0),
+    (11, 6, -0.7, -0.2, 0.0, 0.0),
+    (11, 7, -0.1, -1.7, -0.0, 0.1),
+    (11, 8, 1.4, -1.6, -0.1, -0.0),
+    (11, 9, -0.6, -3.0, -0.1, -0.1),
+    (11, 10, 0.2, -2.0, -0.1, 0.0),
+    (11, 11, 3.1, -2.6, -0.1, -0.0),
+    (12, 0, -2.0, 0.0, 0.0, 0.0),
+    (12, 1, -0.1, -1.2, -0.0, -0.0),
+    (12, 2, 0.5, 0.5, -0.0, 0.0),
+    (12, 3, 1.3, 1.4, 0.0, -0.0),
+    (12, 4, -1.2, -1.8, -0.0, 0.0),
+    (12, 5, 0.7, 0.1, -0.0, -0.0),
+    (12, 6, 0.3, 0.8, 0.0, 0.0),
+    (12, 7, 0.5, -0.2, -0.0, 0.0),
+    (12, 8, -0.2, 0.6, 0.0, 0.1),
+    (12, 9, -0.5, 0.2, -0.0, -0.0),
+    (12, 10, 0.1, -0.9, -0.0, -0.0),
+    (12, 11, -1.1, -0.0, -0.0, 0.0),
+    (12, 12, -0.3, 0.5, -0.1, -0.1),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test values published with WMM2020, at 2020.0 and 0 km
+    #[test]
+    fn test_wmm2020() {
+        // latitude, longitude, X, Y, Z, declination, inclination
+        for (lat, lon, x, y, z, d, i) in [
+            (80.0, 0.0, 6570.4, -146.3, 54606.0, -1.28, 83.14),
+            (0.0, 120.0, 39624.3, 109.9, -10932.5, 0.16, -15.42),
+            (-80.0, 240.0, 5940.6, 15772.1, -52480.8, 69.36, -72.20),
+        ] {
+            let f = WMM2020.field(lat, lon, 0.0, 2020.0);
+            assert!((f.ned[0] - x).abs() < 1.0, "{:?}", f);
+            assert!((f.ned[1] - y).abs() < 1.0, "{:?}", f);
+            assert!((f.ned[2] - z).abs() < 1.0, "{:?}", f);
+            assert!((f.declination - d).abs() < 0.01);
+            assert!((f.inclination - i).abs() < 0.01);
+        }
+    }
+
+    /// At its epoch WMM2025 continues the secular variation of WMM2020 closely
+    #[test]
+    fn test_wmm2025() {
+        for (lat, lon) in [(80.0, 0.0), (0.0, 120.0), (-80.0, 240.0), (45.0, -100.0)] {
+            let new = WMM2025.field(lat, lon, 0.0, 2025.0);
+            let old = WMM2020.field(lat, lon, 0.0, 2025.0);
+            for (a, b) in new.ned.iter().zip(old.ned) {
+                assert!((a - b).abs() < 150.0, "{:?} {:?}", new, old);
+            }
+            assert!((new.declination - old.declination).abs() < 1.0);
+            assert!((new.inclination - old.inclination).abs() < 0.5);
+        }
+
+        let mut degrees = [0u32; MAX_DEGREE + 1];
+        for c in WMM2025.coefficients {
+            assert!(c.m <= c.n);
+            degrees[c.n as usize] += 1;
+        }
+        for (n, count) in degrees.iter().enumerate().skip(1) {
+            assert_eq!(*count, n as u32 + 1);
+        }
+    }
+
+    #[test]
+    fn test_helpers() {
+        assert_eq!(decimal_year(2020, 1, 1), 2020.0);
+        assert!((decimal_year(2021, 7, 2) - 2021.5).abs() < 0.002);
+        assert!(WMM2020.is_valid_for(2024.9));
+        assert!(!WMM2020.is_valid_for(2025.0));
+        assert!(WMM2025.is_valid_for(2029.9));
+        assert_eq!(Model::default(), WMM2025);
+        assert_eq!(for_year(2024.9), WMM2020);
+        assert_eq!(for_year(2026.5), WMM2025);
+        assert_eq!(for_year(2031.0), WMM2025);
+
+        let f = WMM2020.field(0.0, 120.0, 0.0, 2020.0);
+        assert!((f.total_lsb() - f.total / 10.0).abs() < 1e-3);
+        assert!((f.true_heading(359.9) - (359.9 + f.declination - 360.0)).abs() < 1e-3);
+    }
+}